@@ -1,10 +1,46 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use regex::Regex;
+use reqwest::blocking::multipart::{Form, Part};
 use reqwest::blocking::Client;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use serde_json::{json, Value};
 use std::env;
 use std::time::Duration;
 
+use crate::error::{send_with_retry, BotError, Service};
+use crate::metrics;
+
+// Trimmed contents of all <tag>...</tag> elements of an LLM response in order, empty ones skipped
+pub fn extract_tags(response: &str, tag: &str) -> Vec<String> {
+    let tag = regex::escape(tag);
+    let re = Regex::new(&format!(r"(?is)<{}>(.*?)</{}>", tag, tag)).unwrap();
+    re.captures_iter(response)
+        .map(|caps| caps[1].trim().to_string())
+        .filter(|content| !content.is_empty())
+        .collect()
+}
+
+// The first element, an empty string if there is none
+pub fn extract_tag(response: &str, tag: &str) -> String {
+    extract_tags(response, tag)
+        .into_iter()
+        .next()
+        .unwrap_or_default()
+}
+
 pub fn llm(system: &str, user: &str) -> anyhow::Result<String> {
+    metrics::timed(&metrics::LLM_SECONDS, &metrics::LLM_ERRORS, "chat", || {
+        llm_request(system, user)
+    })
+}
+
+#[tracing::instrument(
+    name = "llm",
+    skip_all,
+    fields(model, prompt_tokens, completion_tokens)
+)]
+fn llm_request(system: &str, user: &str) -> anyhow::Result<String> {
     let api_key = env::var("OPENAI_API_KEY")?;
     let model = env::var("CHAT_COMPLETIONS_MODEL")?;
     let payload = json!({
@@ -23,14 +59,124 @@ pub fn llm(system: &str, user: &str) -> anyhow::Result<String> {
     let auth_value = format!("Bearer {}", api_key);
     headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_value)?);
     let client = Client::builder().timeout(Duration::from_secs(60)).build()?;
-    let response = client.post(url).headers(headers).json(&payload).send()?;
+    let response = send_with_retry(Service::Llm, || {
+        client
+            .post(&url)
+            .headers(headers.clone())
+            .json(&payload)
+            .send()
+    })?;
+    let resp_json: Value = response.json()?;
+    record_usage(&model, &resp_json);
+    let content = resp_json["choices"][0]["message"]["content"]
+        .as_str()
+        .ok_or(BotError::permanent(Service::Llm, "No content in response"))?;
+    Ok(content.to_string())
+}
+
+// Sends an image to a vision-capable chat model using multimodal content
+pub fn llm_vision(system: &str, user: &str, image: &[u8]) -> anyhow::Result<String> {
+    metrics::timed(
+        &metrics::LLM_SECONDS,
+        &metrics::LLM_ERRORS,
+        "vision",
+        || llm_vision_request(system, user, image),
+    )
+}
+
+#[tracing::instrument(
+    name = "llm_vision",
+    skip_all,
+    fields(model, prompt_tokens, completion_tokens)
+)]
+fn llm_vision_request(system: &str, user: &str, image: &[u8]) -> anyhow::Result<String> {
+    let api_key = env::var("OPENAI_API_KEY")?;
+    let model = match env::var("VISION_MODEL") {
+        Ok(model) => model,
+        Err(_) => env::var("CHAT_COMPLETIONS_MODEL")?,
+    };
+    let image_url = format!("data:image/jpeg;base64,{}", BASE64.encode(image));
+    let payload = json!({
+        "model": model,
+        "messages": [
+            { "role": "system", "content": system },
+            { "role": "user", "content": [
+                { "type": "text", "text": user },
+                { "type": "image_url", "image_url": { "url": image_url } }
+            ] }
+        ],
+        "temperature": 0.2,
+        "max_tokens": 1000,
+        "stream": false,
+    });
+
+    let url = env::var("CHAT_COMPLETIONS_URL")?;
+    let mut headers = HeaderMap::new();
+    let auth_value = format!("Bearer {}", api_key);
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_value)?);
+    let client = Client::builder()
+        .timeout(Duration::from_secs(120))
+        .build()?;
+    let response = send_with_retry(Service::Llm, || {
+        client
+            .post(&url)
+            .headers(headers.clone())
+            .json(&payload)
+            .send()
+    })?;
     let resp_json: Value = response.json()?;
+    record_usage(&model, &resp_json);
     let content = resp_json["choices"][0]["message"]["content"]
         .as_str()
-        .ok_or(anyhow::anyhow!("No content in response"))?;
+        .ok_or(BotError::permanent(Service::Llm, "No content in response"))?;
     Ok(content.to_string())
 }
 
+#[tracing::instrument(name = "transcribe", skip_all, fields(model, bytes = audio.len()))]
+pub fn transcribe(file_name: &str, audio: Vec<u8>) -> anyhow::Result<String> {
+    let api_key = env::var("OPENAI_API_KEY")?;
+    let model = env::var("TRANSCRIPTIONS_MODEL").unwrap_or_else(|_| "whisper-1".to_string());
+    let url = env::var("TRANSCRIPTIONS_URL")?;
+
+    let mut headers = HeaderMap::new();
+    let auth_value = format!("Bearer {}", api_key);
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(&auth_value)?);
+    let client = Client::builder()
+        .timeout(Duration::from_secs(120))
+        .build()?;
+    tracing::Span::current().record("model", model.as_str());
+    // A multipart body can't be reused, so every attempt builds its own form
+    let response = send_with_retry(Service::Transcription, || {
+        let form = Form::new().text("model", model.clone()).part(
+            "file",
+            Part::bytes(audio.clone()).file_name(file_name.to_string()),
+        );
+        client
+            .post(&url)
+            .headers(headers.clone())
+            .multipart(form)
+            .send()
+    })?;
+    let resp_json: Value = response.json()?;
+    let text = resp_json["text"].as_str().ok_or(BotError::permanent(
+        Service::Transcription,
+        "No text in transcription response",
+    ))?;
+    Ok(text.trim().to_string())
+}
+
+// Token counts go to the current span, so they show up with its latency
+fn record_usage(model: &str, response: &Value) {
+    let span = tracing::Span::current();
+    span.record("model", model);
+    if let Some(tokens) = response["usage"]["prompt_tokens"].as_u64() {
+        span.record("prompt_tokens", tokens);
+    }
+    if let Some(tokens) = response["usage"]["completion_tokens"].as_u64() {
+        span.record("completion_tokens", tokens);
+    }
+}
+
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
@@ -51,6 +197,17 @@ struct EmbeddingResponse {
 }
 
 pub fn emb(input: &str) -> anyhow::Result<Vec<f32>> {
+    let model = env::var("EMBEDDINGS_MODEL")?;
+    metrics::timed(
+        &metrics::EMBEDDING_SECONDS,
+        &metrics::EMBEDDING_ERRORS,
+        &model,
+        || emb_request(input),
+    )
+}
+
+#[tracing::instrument(name = "embedding", skip_all, fields(model))]
+fn emb_request(input: &str) -> anyhow::Result<Vec<f32>> {
     let api_key = env::var("OPENAI_API_KEY")?;
 
     let mut headers = HeaderMap::new();
@@ -60,24 +217,29 @@ pub fn emb(input: &str) -> anyhow::Result<Vec<f32>> {
 
     // lm-kit/text-embedding-bge-m3
     let model = env::var("EMBEDDINGS_MODEL")?;
+    tracing::Span::current().record("model", model.as_str());
     let payload = json!({
         "model": model,
         "input": input,
     });
     let url = env::var("EMBEDDINGS_URL")?;
-    let response = client
-        .post(url)
-        .headers(headers)
-        .header("Content-Type", "application/json")
-        .json(&payload)
-        .send()?
-        .error_for_status()?;
+    let response = send_with_retry(Service::Embeddings, || {
+        client
+            .post(&url)
+            .headers(headers.clone())
+            .header("Content-Type", "application/json")
+            .json(&payload)
+            .send()
+    })?;
 
     let embedding_response: EmbeddingResponse = response.json()?;
 
     if let Some(embedding) = embedding_response.data.into_iter().next() {
         Ok(embedding.embedding)
     } else {
-        Err(anyhow::anyhow!("No embedding data found"))
+        Err(BotError::permanent(
+            Service::Embeddings,
+            "No embedding data found",
+        ))
     }
 }
@@ -0,0 +1,80 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::storage;
+
+const AUDIT_FILE: &str = "audit.jsonl";
+
+// Keeps lines from concurrent writers from interleaving
+static AUDIT_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub user_id: u64,
+    pub user_name: String,
+    pub chat_id: i64,
+    pub action: String,
+    pub details: Value,
+}
+
+// The log is append-only: entries are never rewritten or deleted by the bot
+pub fn record(
+    user_id: u64,
+    user_name: &str,
+    chat_id: i64,
+    action: &str,
+    details: Value,
+) -> anyhow::Result<()> {
+    let entry = Entry {
+        time: Utc::now(),
+        user_id,
+        user_name: user_name.to_string(),
+        chat_id,
+        action: action.to_string(),
+        details,
+    };
+    let line = serde_json::to_string(&entry)?;
+    let _guard = AUDIT_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(storage::data_path(AUDIT_FILE)?)?;
+    writeln!(file, "{}", line)?;
+    Ok(())
+}
+
+// Latest entries first. filter matches a user ID or an action name.
+pub fn query(limit: usize, filter: Option<&str>) -> anyhow::Result<Vec<Entry>> {
+    let path = storage::data_path(AUDIT_FILE)?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)?;
+    let entries = content
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+        .filter(|entry| match filter {
+            Some(filter) => entry.action == filter || entry.user_id.to_string() == filter,
+            None => true,
+        })
+        .take(limit)
+        .collect();
+    Ok(entries)
+}
+
+pub fn format_entry(entry: &Entry) -> String {
+    format!(
+        "{} {} ({}) {} {}",
+        entry.time.format("%Y-%m-%d %H:%M:%S"),
+        entry.user_name,
+        entry.user_id,
+        entry.action,
+        entry.details
+    )
+}
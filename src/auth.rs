@@ -0,0 +1,298 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::error::BotError;
+use crate::storage;
+
+const USERS_FILE: &str = "users.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Member,
+    ReadOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Remember,
+    Forget,
+    RunCommands,
+    ManageUsers,
+}
+
+impl Role {
+    // Everybody can ask questions and chat, the rest depends on the role
+    pub fn can(&self, capability: Capability) -> bool {
+        match self {
+            Role::Admin => true,
+            Role::Member => matches!(capability, Capability::Remember | Capability::Forget),
+            Role::ReadOnly => false,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Role> {
+        match name.to_lowercase().replace('-', "_").as_str() {
+            "admin" => Some(Role::Admin),
+            "member" => Some(Role::Member),
+            "read_only" | "readonly" => Some(Role::ReadOnly),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Admin => "admin",
+            Role::Member => "member",
+            Role::ReadOnly => "read-only",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Capability::Remember => "save information",
+            Capability::Forget => "delete information",
+            Capability::RunCommands => "run commands",
+            Capability::ManageUsers => "manage users",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: u64,
+    pub name: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Registry {
+    users: Vec<User>,
+}
+
+// Telegram user IDs from ADMIN_USER_IDS are admins regardless of the users file
+fn env_admins() -> Vec<u64> {
+    env::var("ADMIN_USER_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse().ok())
+        .collect()
+}
+
+pub fn role_of(user_id: u64) -> anyhow::Result<Option<Role>> {
+    if env_admins().contains(&user_id) {
+        return Ok(Some(Role::Admin));
+    }
+    let registry: Registry = storage::load_json(USERS_FILE)?;
+    Ok(registry
+        .users
+        .iter()
+        .find(|user| user.id == user_id)
+        .map(|user| user.role))
+}
+
+// Without any admin the bot can't be managed, so the first user with the password becomes one
+pub fn has_admin() -> anyhow::Result<bool> {
+    if !env_admins().is_empty() {
+        return Ok(true);
+    }
+    let registry: Registry = storage::load_json(USERS_FILE)?;
+    Ok(registry.users.iter().any(|user| user.role == Role::Admin))
+}
+
+pub fn users() -> anyhow::Result<Vec<User>> {
+    let mut users = storage::load_json::<Registry>(USERS_FILE)?.users;
+    for id in env_admins() {
+        if !users.iter().any(|user| user.id == id) {
+            users.push(User {
+                id,
+                name: "ADMIN_USER_IDS".to_string(),
+                role: Role::Admin,
+            });
+        }
+    }
+    Ok(users)
+}
+
+pub fn invite(user: User) -> anyhow::Result<()> {
+    storage::update_json(USERS_FILE, |registry: &mut Registry| {
+        registry.users.retain(|existing| existing.id != user.id);
+        registry.users.push(user);
+    })
+}
+
+// Returns false if there was no such user
+pub fn revoke(user_id: u64) -> anyhow::Result<bool> {
+    if env_admins().contains(&user_id) {
+        return Err(BotError::user(format!(
+            "User {} is an admin from ADMIN_USER_IDS, remove them there.",
+            user_id
+        )));
+    }
+    storage::update_json(USERS_FILE, |registry: &mut Registry| {
+        let count = registry.users.len();
+        registry.users.retain(|user| user.id != user_id);
+        registry.users.len() != count
+    })
+}
+
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| anyhow::anyhow!("Can't hash password: {}", err))?;
+    Ok(hash.to_string())
+}
+
+// BOT_PASSWORD_HASH is preferred, a plain BOT_PASSWORD is hashed once on first use,
+// so both are checked the same way
+fn password_hash() -> anyhow::Result<String> {
+    if let Ok(hash) = env::var("BOT_PASSWORD_HASH") {
+        return Ok(hash);
+    }
+    static PLAIN_HASH: OnceLock<String> = OnceLock::new();
+    if let Some(hash) = PLAIN_HASH.get() {
+        return Ok(hash.clone());
+    }
+    let hash = hash_password(&env::var("BOT_PASSWORD")?)?;
+    Ok(PLAIN_HASH.get_or_init(|| hash).clone())
+}
+
+pub fn verify_password(input: &str) -> anyhow::Result<bool> {
+    let hash = password_hash()?;
+    let parsed = PasswordHash::new(&hash)
+        .map_err(|err| anyhow::anyhow!("Invalid BOT_PASSWORD_HASH: {}", err))?;
+    Ok(Argon2::default()
+        .verify_password(input.as_bytes(), &parsed)
+        .is_ok())
+}
+
+struct Attempts {
+    failures: u32,
+    locked_until: Option<Instant>,
+}
+
+// Failed password attempts per chat and user, kept in memory only
+static ATTEMPTS: LazyLock<Mutex<HashMap<(i64, u64), Attempts>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn env_number(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+pub fn lockout_remaining(chat_id: i64, user_id: u64) -> Option<Duration> {
+    let attempts = ATTEMPTS.lock().unwrap_or_else(|err| err.into_inner());
+    attempts
+        .get(&(chat_id, user_id))
+        .and_then(|a| a.locked_until)
+        .and_then(|until| until.checked_duration_since(Instant::now()))
+}
+
+// Returns the lockout duration if this failure locked the user out
+pub fn record_failure(chat_id: i64, user_id: u64) -> Option<Duration> {
+    let max_attempts = env_number("MAX_PASSWORD_ATTEMPTS", 5) as u32;
+    let lockout = Duration::from_secs(env_number("PASSWORD_LOCKOUT_MINUTES", 15) * 60);
+    let mut attempts = ATTEMPTS.lock().unwrap_or_else(|err| err.into_inner());
+    let entry = attempts.entry((chat_id, user_id)).or_insert(Attempts {
+        failures: 0,
+        locked_until: None,
+    });
+    entry.failures += 1;
+    if entry.failures >= max_attempts {
+        entry.failures = 0;
+        entry.locked_until = Some(Instant::now() + lockout);
+        Some(lockout)
+    } else {
+        None
+    }
+}
+
+pub fn reset_failures(chat_id: i64, user_id: u64) {
+    let mut attempts = ATTEMPTS.lock().unwrap_or_else(|err| err.into_inner());
+    attempts.remove(&(chat_id, user_id));
+}
+
+// Whole minutes for the lockout messages, rounded up so it never says 0
+pub fn lockout_minutes(duration: Duration) -> u64 {
+    duration.as_millis().div_ceil(60_000) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_have_the_capabilities_of_the_readme_table() {
+        use Capability::*;
+        let all = [Remember, Forget, RunCommands, ManageUsers];
+        assert!(all.iter().all(|capability| Role::Admin.can(*capability)));
+        assert!(Role::Member.can(Remember));
+        assert!(Role::Member.can(Forget));
+        assert!(!Role::Member.can(RunCommands));
+        assert!(!Role::Member.can(ManageUsers));
+        assert!(all
+            .iter()
+            .all(|capability| !Role::ReadOnly.can(*capability)));
+    }
+
+    #[test]
+    fn role_names_are_parsed() {
+        assert_eq!(Role::parse("Admin"), Some(Role::Admin));
+        assert_eq!(Role::parse("read-only"), Some(Role::ReadOnly));
+        assert_eq!(Role::parse("readonly"), Some(Role::ReadOnly));
+        assert_eq!(Role::parse("owner"), None);
+    }
+
+    // Every test uses its own chat, the attempts are global. MAX_PASSWORD_ATTEMPTS is 5 by default.
+    #[test]
+    fn too_many_failures_lock_the_user_out() {
+        for _ in 0..4 {
+            assert_eq!(record_failure(-1, 1), None);
+            assert_eq!(lockout_remaining(-1, 1), None);
+        }
+        let lockout = record_failure(-1, 1).unwrap();
+        assert_eq!(lockout, Duration::from_secs(15 * 60));
+        assert!(lockout_remaining(-1, 1).is_some_and(|remaining| remaining <= lockout));
+        // Other users and chats are not affected
+        assert_eq!(lockout_remaining(-1, 2), None);
+        assert_eq!(lockout_remaining(-2, 1), None);
+    }
+
+    #[test]
+    fn success_resets_the_failures() {
+        for _ in 0..4 {
+            record_failure(-3, 1);
+        }
+        reset_failures(-3, 1);
+        for _ in 0..4 {
+            assert_eq!(record_failure(-3, 1), None);
+        }
+        assert!(record_failure(-3, 1).is_some());
+        reset_failures(-3, 1);
+        assert_eq!(lockout_remaining(-3, 1), None);
+    }
+
+    #[test]
+    fn lockout_minutes_round_up() {
+        assert_eq!(lockout_minutes(Duration::from_secs(15 * 60)), 15);
+        assert_eq!(lockout_minutes(Duration::from_secs(14 * 60 + 1)), 15);
+        assert_eq!(lockout_minutes(Duration::from_millis(500)), 1);
+    }
+}
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::BotError;
+use crate::qdrant;
+
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Export {
+    pub version: u32,
+    pub exported_at: u64,
+    pub memories: Vec<Memory>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Memory {
+    pub id: i32,
+    pub text: String,
+    #[serde(default)]
+    pub metadata: Value,
+}
+
+pub fn export_json() -> anyhow::Result<Vec<u8>> {
+    let mut docs = qdrant::all_documents()?;
+    docs.sort_by_key(|doc| doc.id);
+    let export = Export {
+        version: FORMAT_VERSION,
+        exported_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        memories: docs
+            .into_iter()
+            .map(|doc| Memory {
+                id: doc.id,
+                text: doc.text,
+                metadata: doc.metadata,
+            })
+            .collect(),
+    };
+    Ok(serde_json::to_vec_pretty(&export)?)
+}
+
+// Human-readable variant, can't be imported back
+pub fn export_markdown() -> anyhow::Result<Vec<u8>> {
+    let mut docs = qdrant::all_documents()?;
+    docs.sort_by_key(|doc| doc.id);
+    let mut markdown = String::from("# Memories\n");
+    for doc in docs {
+        markdown.push_str(&format!("\n## {}\n\n{}\n", doc.id, doc.text));
+        if let Some(metadata) = doc.metadata.as_object().filter(|m| !m.is_empty()) {
+            markdown.push('\n');
+            for (key, value) in metadata {
+                markdown.push_str(&format!("- {}: {}\n", key, value));
+            }
+        }
+    }
+    Ok(markdown.into_bytes())
+}
+
+// Re-embeds memories from an export file, skipping the ones that are already stored.
+// Returns the number of imported and skipped memories.
+pub fn import_json(bytes: &[u8]) -> anyhow::Result<(usize, usize)> {
+    let export: Export = serde_json::from_slice(bytes)
+        .map_err(|err| BotError::user(format!("This is not a memory export file: {}", err)))?;
+    if export.version > FORMAT_VERSION {
+        return Err(BotError::user(format!(
+            "Unsupported export version {}",
+            export.version
+        )));
+    }
+    let existing: HashSet<String> = qdrant::all_documents()?
+        .into_iter()
+        .map(|doc| doc.text)
+        .collect();
+    let mut id = qdrant::last_document_id()?;
+    let mut imported = 0;
+    let mut skipped = 0;
+    for memory in export.memories {
+        if existing.contains(&memory.text) {
+            skipped += 1;
+            continue;
+        }
+        id += 1;
+        qdrant::add_document_with_metadata(id, &memory.text, &memory.metadata)?;
+        imported += 1;
+    }
+    Ok((imported, skipped))
+}
@@ -0,0 +1,497 @@
+use serde_json::json;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, ParseMode};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::oneshot;
+
+use crate::hosts::Host;
+use crate::render::{escape, escaped_tail, MESSAGE_CHARS};
+use crate::{audit, error, history, metrics};
+
+// Telegram messages are limited to 4096 characters, the rest of the status needs some room
+const TAIL_CHARS: usize = 3000;
+// Shown of each stream when the output doesn't fit into one message
+const STREAM_CHARS: usize = 1500;
+// Of the command in the message header, long scripts would fill the whole message
+const TITLE_CHARS: usize = 200;
+// Memory limit for the full output of each stream
+const MAX_CAPTURE: usize = 10 * 1024 * 1024;
+const EDIT_INTERVAL: Duration = Duration::from_secs(3);
+// Output passed on in the outcome, e.g. for the LLM to plan the next step
+const OUTCOME_CHARS: usize = 2000;
+
+struct Running {
+    chat_id: ChatId,
+    user_id: u64,
+    command: String,
+    host: Option<String>,
+    started: Instant,
+    kill: Option<oneshot::Sender<()>>,
+}
+
+// Commands running in the background, by job ID
+static RUNNING: LazyLock<Mutex<HashMap<u64, Running>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// How a finished command ended
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    // None if it was killed by a signal
+    pub exit_code: Option<i32>,
+    pub killed: bool,
+    // End of stdout and stderr
+    pub output: String,
+    // Full size of each stream, as captured
+    pub stdout_bytes: usize,
+    pub stderr_bytes: usize,
+}
+
+impl Outcome {
+    pub fn success(&self) -> bool {
+        !self.killed && self.exit_code == Some(0)
+    }
+}
+
+// Starts the command in the background, locally or over SSH on the host, and returns its job ID.
+// The job posts a message with the tail of its output and keeps editing it until the command exits.
+pub fn start(
+    bot: Bot,
+    chat_id: ChatId,
+    user_id: u64,
+    user_name: &str,
+    host: Option<Host>,
+    command: &str,
+) -> u64 {
+    start_watched(bot, chat_id, user_id, user_name, host, command).0
+}
+
+// Like `start`, the receiver gets the outcome when the command exits. It is dropped without one
+// if the command could not be run at all.
+pub fn start_watched(
+    bot: Bot,
+    chat_id: ChatId,
+    user_id: u64,
+    user_name: &str,
+    host: Option<Host>,
+    command: &str,
+) -> (u64, oneshot::Receiver<Outcome>) {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let (kill, killed) = oneshot::channel();
+    RUNNING
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(
+            id,
+            Running {
+                chat_id,
+                user_id,
+                command: command.to_string(),
+                host: host.as_ref().map(|host| host.name.clone()),
+                started: Instant::now(),
+                kill: Some(kill),
+            },
+        );
+    let (done, outcome) = oneshot::channel();
+    let user_name = user_name.to_string();
+    let command = command.to_string();
+    let span = tracing::info_span!("command", job = id, chat_id = chat_id.0);
+    tokio::spawn(tracing::Instrument::instrument(
+        async move {
+            let result = run(&bot, id, chat_id, host.as_ref(), &command, killed).await;
+            let host = host.map(|host| host.name);
+            RUNNING
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .remove(&id);
+            let details = match &result {
+                Ok(outcome) => {
+                    json!({
+                        "command": command,
+                        "host": host,
+                        "job": id,
+                        "exit_code": outcome.exit_code,
+                        "killed": outcome.killed,
+                        "stdout_bytes": outcome.stdout_bytes,
+                        "stderr_bytes": outcome.stderr_bytes,
+                    })
+                }
+                Err(err) => {
+                    json!({ "command": command, "host": host, "job": id, "error": err.to_string() })
+                }
+            };
+            if let Err(err) = audit::record(user_id, &user_name, chat_id.0, "command", details) {
+                tracing::error!(error = %err, "Audit log error");
+            }
+            let (exit_code, killed) = result
+                .as_ref()
+                .map_or((None, false), |outcome| (outcome.exit_code, outcome.killed));
+            if let Err(err) = history::record(
+                chat_id.0,
+                user_id,
+                &command,
+                host.as_deref(),
+                exit_code,
+                killed,
+            ) {
+                tracing::error!(error = %err, "Command history error");
+            }
+            match result {
+                Ok(outcome) => {
+                    let _ = done.send(outcome);
+                }
+                Err(err) => {
+                    tracing::error!(error = format!("{:#}", err), "Command failed");
+                    // Details stay in the log, the chat gets the friendly message in BOT_LANGUAGE
+                    let message = format!("Job {} failed: {}", id, error::user_message(&err, ""));
+                    let _ = bot.send_message(chat_id, message).await;
+                }
+            }
+        },
+        span,
+    ));
+    (id, outcome)
+}
+
+// Running jobs of the chat, oldest first
+pub fn list(chat_id: ChatId) -> Vec<String> {
+    let running = RUNNING.lock().unwrap_or_else(|err| err.into_inner());
+    let mut jobs: Vec<(&u64, &Running)> = running
+        .iter()
+        .filter(|(_, job)| job.chat_id == chat_id)
+        .collect();
+    jobs.sort_by_key(|(id, _)| **id);
+    jobs.iter()
+        .map(|(id, job)| {
+            let host = job
+                .host
+                .as_ref()
+                .map_or(String::new(), |host| format!(" on {}", host));
+            format!(
+                "{}. \"{}\"{} — {}s, started by {}",
+                id,
+                job.command,
+                host,
+                job.started.elapsed().as_secs(),
+                job.user_id
+            )
+        })
+        .collect()
+}
+
+// Only jobs of the same chat can be killed, and only the owner's ones unless `owner` is None.
+// Returns false if there was no such job.
+pub fn kill(chat_id: ChatId, id: u64, owner: Option<u64>) -> bool {
+    let mut running = RUNNING.lock().unwrap_or_else(|err| err.into_inner());
+    match running.get_mut(&id) {
+        Some(job) if job.chat_id == chat_id && owner.is_none_or(|user| user == job.user_id) => {
+            if let Some(kill) = job.kill.take() {
+                let _ = kill.send(());
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+// Output of a running command: the full streams for the report and the interleaved tail
+// for the live message
+#[derive(Default)]
+struct Captured {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    tail: String,
+}
+
+async fn run(
+    bot: &Bot,
+    id: u64,
+    chat_id: ChatId,
+    host: Option<&Host>,
+    command: &str,
+    mut killed: oneshot::Receiver<()>,
+) -> anyhow::Result<Outcome> {
+    let mut process = match host {
+        Some(host) => {
+            let mut process = tokio::process::Command::new("ssh");
+            process.args(host.ssh_args(command));
+            process
+        }
+        None => {
+            let mut process = tokio::process::Command::new("sh");
+            process.arg("-c").arg(command);
+            process
+        }
+    };
+    let mut child = process
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .inspect_err(|_| metrics::COMMANDS.with_label_values(&["error"]).inc())?;
+    let captured = Arc::new(Mutex::new(Captured::default()));
+    let readers = [
+        tokio::spawn(collect(child.stdout.take(), captured.clone(), false)),
+        tokio::spawn(collect(child.stderr.take(), captured.clone(), true)),
+    ];
+    let title = title(command, host.map(|host| host.name.as_str()));
+    let started = Instant::now();
+    let message = bot
+        .send_message(chat_id, status(id, &title, "running", ""))
+        .parse_mode(ParseMode::Html)
+        .await?;
+    let mut shown = String::new();
+    let mut interval = tokio::time::interval(EDIT_INTERVAL);
+    let mut was_killed = false;
+    let exit = loop {
+        tokio::select! {
+            exit = child.wait() => break exit?,
+            _ = &mut killed, if !was_killed => {
+                child.start_kill()?;
+                was_killed = true;
+            }
+            _ = interval.tick() => {
+                let tail = captured.lock().unwrap_or_else(|err| err.into_inner()).tail.clone();
+                if tail != shown {
+                    let text = status(id, &title, &format!("running, {}s", started.elapsed().as_secs()), &tail);
+                    // Edits can be rate limited, the next tick tries again
+                    let edit = bot.edit_message_text(chat_id, message.id, text).parse_mode(ParseMode::Html);
+                    if edit.await.is_ok() {
+                        shown = tail;
+                    }
+                }
+            }
+        }
+    };
+    let duration = started.elapsed();
+    // Background processes of a killed shell can keep the pipes open, don't wait for them
+    for reader in readers {
+        let _ = tokio::time::timeout(Duration::from_secs(1), reader).await;
+    }
+    let label = if was_killed {
+        "killed"
+    } else if exit.success() {
+        "success"
+    } else {
+        "failure"
+    };
+    metrics::COMMANDS.with_label_values(&[label]).inc();
+    tracing::info!(
+        exit_code = exit.code(),
+        killed = was_killed,
+        "Command executed"
+    );
+    let result = match (was_killed, exit.code()) {
+        (true, _) => "killed".to_string(),
+        (false, Some(code)) => format!("exit code {}", code),
+        (false, None) => "terminated by a signal".to_string(),
+    };
+    let state = format!("{} after {}", result, format_duration(duration));
+    let (stdout, stderr, stdout_bytes, stderr_bytes) = {
+        let captured = captured.lock().unwrap_or_else(|err| err.into_inner());
+        (
+            String::from_utf8_lossy(&captured.stdout).into_owned(),
+            String::from_utf8_lossy(&captured.stderr).into_owned(),
+            captured.stdout.len(),
+            captured.stderr.len(),
+        )
+    };
+    let (text, truncated) = report(id, &title, &state, &stdout, &stderr);
+    // The command has finished either way, a failed report must not turn it into a failed job
+    let edit = bot
+        .edit_message_text(chat_id, message.id, text.clone())
+        .parse_mode(ParseMode::Html);
+    if let Err(err) = edit.await {
+        tracing::warn!(job = id, error = %err, "Report not edited, sending it as a new message");
+        let send = bot.send_message(chat_id, text).parse_mode(ParseMode::Html);
+        if let Err(err) = send.await {
+            tracing::error!(job = id, error = %err, "Report not delivered");
+        }
+    }
+    if truncated {
+        let file = format!(
+            "{}$ {}\n{}\n\n--- stdout ---\n{}\n--- stderr ---\n{}",
+            host.map_or(String::new(), |host| host.name.clone()),
+            command,
+            state,
+            stdout,
+            stderr
+        );
+        let send = bot.send_document(
+            chat_id,
+            InputFile::memory(file.into_bytes()).file_name(format!("job-{}-output.txt", id)),
+        );
+        if let Err(err) = send.await {
+            tracing::error!(job = id, error = %err, "Output file not delivered");
+        }
+    }
+    Ok(Outcome {
+        exit_code: exit.code(),
+        killed: was_killed,
+        output: format!(
+            "{}\n{}",
+            tail(stdout.trim_end(), OUTCOME_CHARS),
+            tail(stderr.trim_end(), OUTCOME_CHARS)
+        )
+        .trim()
+        .to_string(),
+        stdout_bytes,
+        stderr_bytes,
+    })
+}
+
+// Reads stdout or stderr in chunks, so output without line breaks or in other encodings
+// shows up too. Keeps at most MAX_CAPTURE bytes of every stream.
+async fn collect(
+    stream: Option<impl AsyncRead + Unpin>,
+    captured: Arc<Mutex<Captured>>,
+    stderr: bool,
+) {
+    let Some(mut stream) = stream else {
+        return;
+    };
+    let mut buf = [0u8; 4096];
+    while let Ok(n) = stream.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        let mut captured = captured.lock().unwrap_or_else(|err| err.into_inner());
+        let full = if stderr {
+            &mut captured.stderr
+        } else {
+            &mut captured.stdout
+        };
+        let room = MAX_CAPTURE.saturating_sub(full.len());
+        full.extend_from_slice(&buf[..n.min(room)]);
+        captured.tail.push_str(&String::from_utf8_lossy(&buf[..n]));
+        captured.tail = tail(&captured.tail, TAIL_CHARS).to_string();
+    }
+}
+
+// The last `max` characters of the text
+fn tail(text: &str, max: usize) -> &str {
+    let excess = text.chars().count().saturating_sub(max);
+    let cut = text.char_indices().nth(excess).map_or(0, |(i, _)| i);
+    &text[cut..]
+}
+
+// The command as HTML, cut if it is long, with the host if it runs over SSH
+fn title(command: &str, host: Option<&str>) -> String {
+    let command = if command.chars().count() > TITLE_CHARS {
+        format!("{}…", command.chars().take(TITLE_CHARS).collect::<String>())
+    } else {
+        command.to_string()
+    };
+    match host {
+        Some(host) => format!("<code>{}</code> on {}", escape(&command), escape(host)),
+        None => format!("<code>{}</code>", escape(&command)),
+    }
+}
+
+fn status(id: u64, title: &str, state: &str, tail: &str) -> String {
+    let header = format!("Job {} {} — {}", id, title, state);
+    if tail.trim().is_empty() {
+        header
+    } else {
+        let room = MESSAGE_CHARS.saturating_sub(header.chars().count() + "\n<pre></pre>".len());
+        format!(
+            "{}\n<pre>{}</pre>",
+            header,
+            escaped_tail(tail, room.min(TAIL_CHARS))
+        )
+    }
+}
+
+// Final message with both streams. Returns true if they had to be cut to fit the message,
+// then the full output is sent as a file.
+fn report(id: u64, title: &str, state: &str, stdout: &str, stderr: &str) -> (String, bool) {
+    let header = format!("Job {} {} — {}", id, title, state);
+    let streams = [("stdout", stdout.trim_end()), ("stderr", stderr.trim_end())];
+    let full_length = header.chars().count()
+        + streams
+            .iter()
+            .map(|(name, output)| name.len() + escape(output).chars().count() + 20)
+            .sum::<usize>();
+    let truncated = full_length > MESSAGE_CHARS;
+    let note = "\nThe output is too long, the full version is in the file.";
+    // What is left after the header, the note and the markup of both streams
+    let room = MESSAGE_CHARS.saturating_sub(header.chars().count() + note.len() + 2 * 22);
+    let stream_chars = (room / 2).min(STREAM_CHARS);
+    let mut text = header;
+    if streams.iter().all(|(_, output)| output.is_empty()) {
+        text.push_str("\nNo output.");
+    }
+    for (name, output) in streams.iter().filter(|(_, output)| !output.is_empty()) {
+        let shown = if truncated {
+            format!("…{}", escaped_tail(output, stream_chars))
+        } else {
+            escape(output)
+        };
+        text.push_str(&format!("\n{}:\n<pre>{}</pre>", name, shown));
+    }
+    if truncated {
+        text.push_str(note);
+    }
+    (text, truncated)
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else {
+        format!("{}m {}s", seconds / 60, seconds % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_keeps_short_output_whole() {
+        let (text, truncated) = report(1, &title("ls", None), "exit code 0", "a<b\n", "");
+        assert!(!truncated);
+        assert_eq!(
+            text,
+            "Job 1 <code>ls</code> — exit code 0\nstdout:\n<pre>a&lt;b</pre>"
+        );
+        let (text, _) = report(1, &title("true", None), "exit code 0", "", "");
+        assert!(text.ends_with("No output."));
+    }
+
+    #[test]
+    fn report_fits_long_output_into_one_message() {
+        let title = title(&"&".repeat(5000), Some("server"));
+        let stdout = "<".repeat(10_000);
+        let stderr = "e".repeat(10_000);
+        let (text, truncated) = report(1, &title, "exit code 1", &stdout, &stderr);
+        assert!(truncated);
+        assert!(text.chars().count() <= MESSAGE_CHARS);
+        assert!(text.contains("stdout:") && text.contains("stderr:"));
+        assert!(text.ends_with("the full version is in the file."));
+    }
+
+    #[test]
+    fn status_fits_a_long_tail_into_one_message() {
+        let title = title(&"<".repeat(5000), None);
+        let text = status(1, &title, "running, 3s", &"&".repeat(10_000));
+        assert!(text.chars().count() <= MESSAGE_CHARS);
+    }
+
+    #[test]
+    fn title_cuts_long_commands() {
+        assert_eq!(title("ls", Some("web")), "<code>ls</code> on web");
+        let long = title(&"x".repeat(1000), None);
+        assert_eq!(
+            long.chars().count(),
+            TITLE_CHARS + "<code>…</code>".chars().count()
+        );
+    }
+}
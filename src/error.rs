@@ -0,0 +1,275 @@
+use reqwest::blocking::Response;
+use reqwest::StatusCode;
+use std::env;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    Llm,
+    Embeddings,
+    Transcription,
+    Qdrant,
+    Web,
+}
+
+#[derive(Debug)]
+pub enum BotError {
+    // Timeouts, connection errors, 429 and 5xx, worth retrying
+    Transient { service: Service, detail: String },
+    // Other 4xx and malformed responses, retrying won't help
+    Permanent { service: Service, detail: String },
+    // Expected outcomes shown to the user as is, e.g. missing permissions
+    User(String),
+}
+
+impl BotError {
+    pub fn user(message: impl Into<String>) -> anyhow::Error {
+        BotError::User(message.into()).into()
+    }
+
+    pub fn permanent(service: Service, detail: impl Into<String>) -> anyhow::Error {
+        BotError::Permanent {
+            service,
+            detail: detail.into(),
+        }
+        .into()
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(self, BotError::Transient { .. })
+    }
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::Transient { service, detail } => {
+                write!(f, "{:?} is temporarily unavailable: {}", service, detail)
+            }
+            BotError::Permanent { service, detail } => {
+                write!(f, "{:?} request failed: {}", service, detail)
+            }
+            BotError::User(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for BotError {}
+
+fn classify(service: Service, result: reqwest::Result<Response>) -> Result<Response, BotError> {
+    match result {
+        Ok(response) if response.status().is_success() => Ok(response),
+        Ok(response) => {
+            let status = response.status();
+            let body = response.text().unwrap_or_default();
+            let detail = format!("{} {}", status, body.chars().take(500).collect::<String>());
+            if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                Err(BotError::Transient { service, detail })
+            } else {
+                Err(BotError::Permanent { service, detail })
+            }
+        }
+        Err(err) if err.is_timeout() || err.is_connect() || err.is_request() => {
+            Err(BotError::Transient {
+                service,
+                detail: err.to_string(),
+            })
+        }
+        Err(err) => Err(BotError::Permanent {
+            service,
+            detail: err.to_string(),
+        }),
+    }
+}
+
+// Sends the request built by `request`, retrying transient failures with exponential backoff
+// (0.5s, 1s, 2s, ...). RETRY_ATTEMPTS sets the total number of attempts, 3 by default.
+// Non-success statuses are turned into errors, so the response is always a success.
+pub fn send_with_retry(
+    service: Service,
+    request: impl Fn() -> reqwest::Result<Response>,
+) -> anyhow::Result<Response> {
+    let attempts = env::var("RETRY_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(3)
+        .max(1);
+    let mut delay = Duration::from_millis(500);
+    let mut attempt = 1;
+    loop {
+        match classify(service, request()) {
+            Ok(response) => return Ok(response),
+            Err(err) if err.is_transient() && attempt < attempts => {
+                tracing::warn!(attempt, error = %err, "Retrying after {:?}", delay);
+                thread::sleep(delay);
+                delay = (delay * 2).min(Duration::from_secs(30));
+                attempt += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+// Friendly text for the user, the details go to the logs. Language is the Telegram
+// client's language, BOT_LANGUAGE is used when it is not supported.
+pub fn user_message(err: &anyhow::Error, language: &str) -> String {
+    let russian = match language {
+        "ru" => true,
+        "en" => false,
+        _ => env::var("BOT_LANGUAGE").is_ok_and(|lang| lang == "ru"),
+    };
+    let text = match err.downcast_ref::<BotError>() {
+        Some(BotError::User(message)) => return message.clone(),
+        Some(BotError::Transient { service, .. }) => match (service, russian) {
+            (Service::Qdrant, false) => {
+                "Memory storage is temporarily unavailable. Please try again in a minute."
+            }
+            (Service::Qdrant, true) => {
+                "Хранилище памяти временно недоступно. Попробуйте через минуту."
+            }
+            (Service::Web, false) => {
+                "The page could not be loaded right now. Please try again later."
+            }
+            (Service::Web, true) => "Не удалось загрузить страницу. Попробуйте позже.",
+            (_, false) => "The AI service is busy or unavailable. Please try again in a minute.",
+            (_, true) => "AI-сервис перегружен или недоступен. Попробуйте через минуту.",
+        },
+        Some(BotError::Permanent { service, .. }) => match (service, russian) {
+            (Service::Qdrant, false) => "Memory storage could not process this request.",
+            (Service::Qdrant, true) => "Хранилище памяти не смогло обработать запрос.",
+            (Service::Web, false) => "The page could not be loaded.",
+            (Service::Web, true) => "Не удалось загрузить страницу.",
+            (Service::Transcription, false) => "The voice message could not be recognized.",
+            (Service::Transcription, true) => "Не удалось распознать голосовое сообщение.",
+            (_, false) => "The AI service could not process this request.",
+            (_, true) => "AI-сервис не смог обработать запрос.",
+        },
+        None if russian => "Что-то пошло не так. Попробуйте позже.",
+        None => "Something went wrong. Please try again later.",
+    };
+    text.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::blocking::Client;
+    use serial_test::serial;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // Sends a request to a local server that answers once with the status line, or not at all
+    fn request(status: Option<&'static str>) -> reqwest::Result<Response> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf);
+            match status {
+                Some(status) => {
+                    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                    let _ = socket.write_all(response.as_bytes());
+                }
+                None => thread::sleep(Duration::from_secs(2)),
+            }
+        });
+        Client::builder()
+            .timeout(Duration::from_millis(300))
+            .build()
+            .unwrap()
+            .get(format!("http://{}/", addr))
+            .send()
+    }
+
+    fn is_transient(result: reqwest::Result<Response>) -> Option<bool> {
+        classify(Service::Llm, result)
+            .err()
+            .map(|err| err.is_transient())
+    }
+
+    #[test]
+    fn success_passes() {
+        assert_eq!(is_transient(request(Some("200 OK"))), None);
+    }
+
+    #[test]
+    fn rate_limits_server_errors_and_timeouts_are_transient() {
+        assert_eq!(
+            is_transient(request(Some("429 Too Many Requests"))),
+            Some(true)
+        );
+        assert_eq!(
+            is_transient(request(Some("503 Service Unavailable"))),
+            Some(true)
+        );
+        assert_eq!(is_transient(request(None)), Some(true));
+    }
+
+    #[test]
+    fn connection_errors_are_transient() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let result = Client::new().get(format!("http://{}/", addr)).send();
+        assert_eq!(is_transient(result), Some(true));
+    }
+
+    #[test]
+    fn other_client_errors_are_permanent() {
+        assert_eq!(is_transient(request(Some("400 Bad Request"))), Some(false));
+        assert_eq!(is_transient(request(Some("401 Unauthorized"))), Some(false));
+        assert_eq!(is_transient(request(Some("404 Not Found"))), Some(false));
+    }
+
+    #[test]
+    #[serial]
+    fn user_message_follows_the_client_language() {
+        env::remove_var("BOT_LANGUAGE");
+        let err = BotError::permanent(Service::Qdrant, "400");
+        assert_eq!(
+            user_message(&err, "en"),
+            "Memory storage could not process this request."
+        );
+        assert_eq!(
+            user_message(&err, "ru"),
+            "Хранилище памяти не смогло обработать запрос."
+        );
+        assert_eq!(
+            user_message(&err, "de"),
+            "Memory storage could not process this request."
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn user_message_falls_back_to_bot_language() {
+        env::set_var("BOT_LANGUAGE", "ru");
+        let transient = BotError::Transient {
+            service: Service::Llm,
+            detail: "503".to_string(),
+        }
+        .into();
+        assert_eq!(
+            user_message(&transient, "de"),
+            "AI-сервис перегружен или недоступен. Попробуйте через минуту."
+        );
+        assert_eq!(
+            user_message(&transient, "en"),
+            "The AI service is busy or unavailable. Please try again in a minute."
+        );
+        assert_eq!(
+            user_message(&anyhow::anyhow!("disk full"), ""),
+            "Что-то пошло не так. Попробуйте позже."
+        );
+        env::remove_var("BOT_LANGUAGE");
+    }
+
+    #[test]
+    fn user_errors_are_shown_as_they_are() {
+        let err = BotError::user("Host web is not registered.");
+        assert_eq!(user_message(&err, "ru"), "Host web is not registered.");
+    }
+}
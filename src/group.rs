@@ -0,0 +1,132 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use teloxide::types::{Message, UserId};
+
+use crate::{ai, qdrant, storage};
+
+const GROUPS_FILE: &str = "groups.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Groups {
+    // Group chats where facts are learned from every message, not only from the ones to the bot
+    learning: Vec<i64>,
+}
+
+pub fn is_group(message: &Message) -> bool {
+    message.chat.is_group() || message.chat.is_supergroup()
+}
+
+// In a group the bot only reacts to commands and to messages that mention it or reply to it
+pub fn is_addressed(message: &Message, bot_id: UserId, username: &str) -> bool {
+    let replied_to_bot = message
+        .reply_to_message()
+        .and_then(|reply| reply.from.as_ref())
+        .is_some_and(|user| user.id == bot_id);
+    let text = message.text().or(message.caption()).unwrap_or_default();
+    replied_to_bot || addresses(text, username)
+}
+
+// "/jobs" and "/jobs@bot" are for the bot, "/jobs@other_bot" is not
+fn addresses(text: &str, username: &str) -> bool {
+    if let Some(command) = text.strip_prefix('/') {
+        let command = command.split(char::is_whitespace).next().unwrap_or_default();
+        return match command.split_once('@') {
+            Some((_, bot)) => bot.eq_ignore_ascii_case(username),
+            None => !command.is_empty(),
+        };
+    }
+    mention(username).is_match(text)
+}
+
+// "@bot what is ..." becomes "what is ...", "/export@bot md" becomes "/export md"
+pub fn strip_mention(text: &str, username: &str) -> String {
+    mention(username).replace_all(text, "").trim().to_string()
+}
+
+fn mention(username: &str) -> Regex {
+    Regex::new(&format!(r"(?i)@{}\b", regex::escape(username))).unwrap()
+}
+
+pub fn is_learning(chat_id: i64) -> anyhow::Result<bool> {
+    let groups: Groups = storage::load_json(GROUPS_FILE)?;
+    Ok(groups.learning.contains(&chat_id))
+}
+
+pub fn set_learning(chat_id: i64, enabled: bool) -> anyhow::Result<()> {
+    storage::update_json(GROUPS_FILE, |groups: &mut Groups| {
+        groups.learning.retain(|id| *id != chat_id);
+        if enabled {
+            groups.learning.push(chat_id);
+        }
+    })
+}
+
+// The fact a group message states, if it has one. Needs the LLM, so it runs before the
+// states lock is taken.
+pub fn extract_fact(text: &str, user_name: &str) -> anyhow::Result<Option<String>> {
+    let user = format!(
+        "<message author=\"{}\">{}</message>\n\
+         If the message states a fact worth remembering for later (a decision, a date, \
+         a contact, a setting, an agreement), rewrite it as one self-contained sentence \
+         that mentions the author. Otherwise answer NONE.",
+        user_name, text
+    );
+    let fact = ai::llm(
+        "You extract long-term facts from group chat messages. Answer with the fact only.",
+        &user,
+    )?;
+    let fact = fact.trim();
+    if fact.is_empty() || fact.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    Ok(Some(fact.to_string()))
+}
+
+// Saves the fact and returns its document ID.
+// The caller must hold the states lock, like every other memory write.
+pub fn save_fact(fact: &str, chat_id: i64, user_id: u64) -> anyhow::Result<i32> {
+    let id = qdrant::last_document_id()? + 1;
+    let metadata = json!({ "source": "group", "chat_id": chat_id, "user_id": user_id });
+    qdrant::add_document_with_metadata(id, fact, &metadata)?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_and_mentions_address_the_bot() {
+        assert!(addresses("/jobs", "helper_bot"));
+        assert!(addresses("/history 5", "helper_bot"));
+        assert!(addresses("/jobs@Helper_Bot", "helper_bot"));
+        assert!(addresses(
+            "@helper_bot what is the wifi password?",
+            "helper_bot"
+        ));
+        assert!(addresses("ask @HELPER_BOT about it", "helper_bot"));
+    }
+
+    #[test]
+    fn other_messages_do_not_address_the_bot() {
+        assert!(!addresses("/jobs@other_bot", "helper_bot"));
+        assert!(!addresses("/ what", "helper_bot"));
+        assert!(!addresses("the deploy is at 5", "helper_bot"));
+        assert!(!addresses("@helper_bot_two hi", "helper_bot"));
+        assert!(!addresses("mail me at x@helper_botnet.com", "helper_bot"));
+    }
+
+    #[test]
+    fn strip_mention_removes_the_bot_name() {
+        assert_eq!(
+            strip_mention("@helper_bot what is up", "helper_bot"),
+            "what is up"
+        );
+        assert_eq!(
+            strip_mention("/export@Helper_Bot md", "helper_bot"),
+            "/export md"
+        );
+        assert_eq!(strip_mention("no mention", "helper_bot"), "no mention");
+    }
+}
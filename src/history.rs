@@ -0,0 +1,106 @@
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::storage;
+
+const HISTORY_FILE: &str = "history.json";
+// Older entries of a chat are dropped
+const MAX_PER_CHAT: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: u64,
+    pub chat_id: i64,
+    pub user_id: u64,
+    pub command: String,
+    pub host: Option<String>,
+    pub at: DateTime<Utc>,
+    // None if it was killed by a signal or could not be run
+    pub exit_code: Option<i32>,
+    pub killed: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct History {
+    next_id: u64,
+    entries: Vec<Entry>,
+}
+
+pub fn record(
+    chat_id: i64,
+    user_id: u64,
+    command: &str,
+    host: Option<&str>,
+    exit_code: Option<i32>,
+    killed: bool,
+) -> anyhow::Result<()> {
+    storage::update_json(HISTORY_FILE, |history: &mut History| {
+        history.next_id += 1;
+        history.entries.push(Entry {
+            id: history.next_id,
+            chat_id,
+            user_id,
+            command: command.to_string(),
+            host: host.map(str::to_string),
+            at: Utc::now(),
+            exit_code,
+            killed,
+        });
+        let count = history
+            .entries
+            .iter()
+            .filter(|entry| entry.chat_id == chat_id)
+            .count();
+        if count > MAX_PER_CHAT {
+            let mut excess = count - MAX_PER_CHAT;
+            history.entries.retain(|entry| {
+                if entry.chat_id == chat_id && excess > 0 {
+                    excess -= 1;
+                    return false;
+                }
+                true
+            });
+        }
+    })
+}
+
+// The chat's last `limit` commands, newest first
+pub fn list(chat_id: i64, limit: usize) -> anyhow::Result<Vec<Entry>> {
+    let history: History = storage::load_json(HISTORY_FILE)?;
+    Ok(history
+        .entries
+        .into_iter()
+        .rev()
+        .filter(|entry| entry.chat_id == chat_id)
+        .take(limit)
+        .collect())
+}
+
+// Only entries of the same chat can be found, so a button can't re-run another chat's command
+pub fn find(chat_id: i64, id: u64) -> anyhow::Result<Option<Entry>> {
+    let history: History = storage::load_json(HISTORY_FILE)?;
+    Ok(history
+        .entries
+        .into_iter()
+        .find(|entry| entry.id == id && entry.chat_id == chat_id))
+}
+
+pub fn format_entry(entry: &Entry) -> String {
+    let at = entry.at.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+    let host = entry
+        .host
+        .as_ref()
+        .map_or(String::new(), |host| format!(" on {}", host));
+    let result = match (entry.killed, entry.exit_code) {
+        (true, _) => "killed".to_string(),
+        (false, Some(code)) => format!("exit code {}", code),
+        (false, None) => "failed".to_string(),
+    };
+    // Long scripts are cut to keep the list readable, a long list is split into several messages
+    let command = if entry.command.chars().count() > 200 {
+        format!("{}…", entry.command.chars().take(200).collect::<String>())
+    } else {
+        entry.command.clone()
+    };
+    format!("{}. {} `{}`{} — {}", entry.id, at, command, host, result)
+}
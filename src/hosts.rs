@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::BotError;
+use crate::storage;
+
+const HOSTS_FILE: &str = "hosts.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Host {
+    pub name: String,
+    // user@host or user@host:port
+    pub address: String,
+    // Path to the private key inside the bot's container, the SSH agent/config is used without it
+    pub key: Option<String>,
+}
+
+impl Host {
+    // Arguments for `ssh`. BatchMode fails instead of asking for a password, new host keys are
+    // trusted on first use and checked afterwards.
+    pub fn ssh_args(&self, command: &str) -> Vec<String> {
+        let mut args = vec![
+            "-o".to_string(),
+            "BatchMode=yes".to_string(),
+            "-o".to_string(),
+            "StrictHostKeyChecking=accept-new".to_string(),
+            "-o".to_string(),
+            "ConnectTimeout=10".to_string(),
+        ];
+        if let Some(key) = &self.key {
+            args.push("-i".to_string());
+            args.push(key.clone());
+        }
+        let destination = match self.address.rsplit_once(':') {
+            Some((destination, port)) if port.parse::<u16>().is_ok() => {
+                args.push("-p".to_string());
+                args.push(port.to_string());
+                destination
+            }
+            _ => &self.address,
+        };
+        args.push(destination.to_string());
+        args.push("--".to_string());
+        args.push(command.to_string());
+        args
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Registry {
+    hosts: Vec<Host>,
+}
+
+pub fn hosts() -> anyhow::Result<Vec<Host>> {
+    Ok(storage::load_json::<Registry>(HOSTS_FILE)?.hosts)
+}
+
+// Host names are matched case-insensitively
+pub fn find(name: &str) -> anyhow::Result<Option<Host>> {
+    Ok(hosts()?
+        .into_iter()
+        .find(|host| host.name.eq_ignore_ascii_case(name)))
+}
+
+pub fn add(host: Host) -> anyhow::Result<()> {
+    if host.name.is_empty()
+        || !host
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        return Err(BotError::user(
+            "Host names may contain only letters, digits, '-', '_' and '.'",
+        ));
+    }
+    if host.address.starts_with('-') || host.address.contains(char::is_whitespace) {
+        return Err(BotError::user(format!(
+            "Invalid address \"{}\"",
+            host.address
+        )));
+    }
+    storage::update_json(HOSTS_FILE, |registry: &mut Registry| {
+        registry
+            .hosts
+            .retain(|existing| !existing.name.eq_ignore_ascii_case(&host.name));
+        registry.hosts.push(host);
+    })
+}
+
+// Returns false if there was no such host
+pub fn remove(name: &str) -> anyhow::Result<bool> {
+    storage::update_json(HOSTS_FILE, |registry: &mut Registry| {
+        let count = registry.hosts.len();
+        registry
+            .hosts
+            .retain(|host| !host.name.eq_ignore_ascii_case(name));
+        registry.hosts.len() != count
+    })
+}
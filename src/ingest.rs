@@ -0,0 +1,175 @@
+use regex::Regex;
+use serde_json::{json, Value};
+use std::io::{Cursor, Read};
+
+use crate::error::BotError;
+use crate::qdrant;
+
+// Roughly one or two paragraphs, small enough for a precise embedding
+pub const CHUNK_SIZE: usize = 1000;
+
+// Extracts text from a file and splits it into chunks for `save_chunks`
+pub fn file_chunks(file_name: &str, bytes: &[u8]) -> anyhow::Result<Vec<String>> {
+    let text = extract_text(file_name, bytes)?;
+    let chunks = split_into_chunks(&text, CHUNK_SIZE);
+    if chunks.is_empty() {
+        return Err(BotError::user(format!("No text found in {}", file_name)));
+    }
+    Ok(chunks)
+}
+
+// Stores chunks as separate documents, each with the common metadata plus its position
+pub fn save_chunks(chunks: &[String], metadata: &Value) -> anyhow::Result<()> {
+    let mut id = qdrant::last_document_id()?;
+    for (index, chunk) in chunks.iter().enumerate() {
+        id += 1;
+        let mut chunk_metadata = metadata.clone();
+        chunk_metadata["chunk"] = json!(index + 1);
+        chunk_metadata["chunks"] = json!(chunks.len());
+        qdrant::add_document_with_metadata(id, chunk, &chunk_metadata)?;
+    }
+    Ok(())
+}
+
+pub fn extract_text(file_name: &str, bytes: &[u8]) -> anyhow::Result<String> {
+    let extension = file_name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "txt" | "md" | "markdown" => Ok(String::from_utf8_lossy(bytes).to_string()),
+        "pdf" => Ok(pdf_extract::extract_text_from_mem(bytes)?),
+        "docx" => extract_docx(bytes),
+        _ => Err(BotError::user(
+            "Unsupported file type. Send PDF, TXT, Markdown or DOCX.",
+        )),
+    }
+}
+
+// DOCX is a zip archive, the text lives in word/document.xml
+fn extract_docx(bytes: &[u8]) -> anyhow::Result<String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+    let mut xml = String::new();
+    archive
+        .by_name("word/document.xml")?
+        .read_to_string(&mut xml)?;
+    // Paragraphs are separated by an empty line, like in the other formats
+    let xml = xml.replace("</w:p>", "\n\n").replace("<w:tab/>", "\t");
+    let re = Regex::new(r"<[^>]+>").unwrap();
+    let text = re.replace_all(&xml, "");
+    Ok(unescape_xml(&text))
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Splits text by paragraphs, merging small ones and cutting large ones by words. A word longer
+// than a chunk (text without spaces, base64, ...) is cut anywhere.
+pub fn split_into_chunks(text: &str, max_len: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let paragraphs = text.split("\n\n").map(str::trim).filter(|p| !p.is_empty());
+    for paragraph in paragraphs {
+        if !current.is_empty() && current.len() + paragraph.len() + 2 > max_len {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if paragraph.len() > max_len {
+            for word in paragraph
+                .split_whitespace()
+                .flat_map(|word| cut(word, max_len))
+            {
+                if !current.is_empty() && current.len() + word.len() + 1 > max_len {
+                    chunks.push(std::mem::take(&mut current));
+                }
+                if !current.is_empty() {
+                    current.push(' ');
+                }
+                current.push_str(word);
+            }
+        } else {
+            if !current.is_empty() {
+                current.push_str("\n\n");
+            }
+            current.push_str(paragraph);
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+// Pieces of at most `max_len` bytes, cut at char boundaries
+fn cut(word: &str, max_len: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = word;
+    while rest.len() > max_len {
+        let mut end = max_len;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        // A single char longer than the limit
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        pieces.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    pieces.push(rest);
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn small_paragraphs_are_merged() {
+        let chunks = split_into_chunks("first\n\nsecond\n\n\n\nthird", 15);
+        assert_eq!(chunks, vec!["first\n\nsecond", "third"]);
+    }
+
+    #[test]
+    fn large_paragraphs_are_cut_by_words() {
+        let text = vec!["word"; 100].join(" ");
+        let chunks = split_into_chunks(&text, 50);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 50));
+        assert_eq!(chunks.join(" "), text);
+    }
+
+    #[test]
+    fn long_words_are_cut_at_char_boundaries() {
+        let text = "漢字".repeat(100);
+        let chunks = split_into_chunks(&text, 50);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 50));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn docx_paragraphs_become_separate_paragraphs() {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(
+            "word/document.xml",
+            zip::write::SimpleFileOptions::default(),
+        )
+        .unwrap();
+        zip.write_all(
+            b"<w:document><w:body>\
+              <w:p><w:r><w:t>Tom &amp; Jerry</w:t></w:r></w:p>\
+              <w:p><w:r><w:t>a</w:t><w:tab/><w:t>b</w:t></w:r></w:p>\
+              </w:body></w:document>",
+        )
+        .unwrap();
+        let bytes = zip.finish().unwrap().into_inner();
+        let text = extract_docx(&bytes).unwrap();
+        assert_eq!(text.trim(), "Tom & Jerry\n\na\tb");
+        assert_eq!(split_into_chunks(&text, 1000), vec!["Tom & Jerry\n\na\tb"]);
+    }
+}
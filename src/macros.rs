@@ -0,0 +1,329 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+
+use crate::error::BotError;
+use crate::storage;
+
+const MACROS_FILE: &str = "macros.json";
+
+static PARAMETER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap());
+
+// A named sequence of commands of one chat. Steps may contain {parameters}, which are given
+// when the macro is run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Macro {
+    pub name: String,
+    pub chat_id: i64,
+    pub user_id: u64,
+    // Registered host the steps run on, locally if None
+    pub host: Option<String>,
+    pub steps: Vec<String>,
+}
+
+impl Macro {
+    // Parameter names in the order they first appear
+    pub fn parameters(&self) -> Vec<String> {
+        let mut parameters: Vec<String> = Vec::new();
+        for step in &self.steps {
+            for caps in PARAMETER.captures_iter(step) {
+                if !parameters.iter().any(|name| name == &caps[1]) {
+                    parameters.push(caps[1].to_string());
+                }
+            }
+        }
+        parameters
+    }
+
+    // Steps with the arguments filled in: name=value pairs and then positional values
+    // in the order of the parameters
+    pub fn expand(&self, arguments: &[String]) -> anyhow::Result<Vec<String>> {
+        let parameters = self.parameters();
+        let mut values: Vec<(String, String)> = Vec::new();
+        let mut positional = Vec::new();
+        for argument in arguments {
+            match argument.split_once('=') {
+                Some((name, value)) if parameters.iter().any(|known| known == name) => {
+                    values.push((name.to_string(), value.to_string()))
+                }
+                _ => positional.push(argument.clone()),
+            }
+        }
+        let mut positional = positional.into_iter();
+        let mut missing = Vec::new();
+        for name in parameters.clone() {
+            if values.iter().any(|(given, _)| given == &name) {
+                continue;
+            }
+            match positional.next() {
+                Some(value) => values.push((name, value)),
+                None => missing.push(name),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(BotError::user(format!(
+                "Macro {} needs {}. Usage: {} {}",
+                self.name,
+                missing.join(", "),
+                self.name,
+                parameters
+                    .iter()
+                    .map(|name| format!("<{}>", name))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            )));
+        }
+        Ok(self
+            .steps
+            .iter()
+            .map(|step| {
+                PARAMETER
+                    .replace_all(step, |caps: &regex::Captures| {
+                        values
+                            .iter()
+                            .find(|(name, _)| name == &caps[1])
+                            .map_or(caps[0].to_string(), |(_, value)| value.clone())
+                    })
+                    .into_owned()
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Macros {
+    macros: Vec<Macro>,
+}
+
+pub fn list(chat_id: i64) -> anyhow::Result<Vec<Macro>> {
+    let macros: Macros = storage::load_json(MACROS_FILE)?;
+    Ok(macros
+        .macros
+        .into_iter()
+        .filter(|saved| saved.chat_id == chat_id)
+        .collect())
+}
+
+// Replaces the chat's macro with the same name
+pub fn add(saved: Macro) -> anyhow::Result<()> {
+    // A name of only separators would match every message
+    if !saved.name.chars().any(|c| c.is_ascii_alphanumeric())
+        || !saved
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(BotError::user(
+            "Macro names need a letter or digit and may contain only letters, digits, '-' and '_'",
+        ));
+    }
+    if saved.steps.is_empty() {
+        return Err(BotError::user("A macro needs at least one command."));
+    }
+    storage::update_json(MACROS_FILE, |macros: &mut Macros| {
+        macros.macros.retain(|existing| {
+            !(existing.chat_id == saved.chat_id && existing.name.eq_ignore_ascii_case(&saved.name))
+        });
+        macros.macros.push(saved);
+    })
+}
+
+// Only the owner's macros unless `owner` is None. Returns false if there was no such macro.
+pub fn remove(chat_id: i64, name: &str, owner: Option<u64>) -> anyhow::Result<bool> {
+    storage::update_json(MACROS_FILE, |macros: &mut Macros| {
+        let count = macros.macros.len();
+        macros.macros.retain(|saved| {
+            !(saved.chat_id == chat_id
+                && saved.name.eq_ignore_ascii_case(name)
+                && owner.is_none_or(|user| user == saved.user_id))
+        });
+        macros.macros.len() != count
+    })
+}
+
+// A message that starts with a macro name, optionally after "/" or "run", and the words after it
+// as arguments, at most one per parameter. "deploy staging main" matches deploy-staging with the argument "main".
+pub fn find(chat_id: i64, message: &str) -> anyhow::Result<Option<(Macro, Vec<String>)>> {
+    Ok(find_in(list(chat_id)?, message))
+}
+
+fn find_in(macros: Vec<Macro>, message: &str) -> Option<(Macro, Vec<String>)> {
+    let message = message.trim().trim_start_matches('/');
+    let words: Vec<&str> = match message.split_whitespace().collect::<Vec<&str>>() {
+        words
+            if words
+                .first()
+                .is_some_and(|word| word.eq_ignore_ascii_case("run")) =>
+        {
+            words[1..].to_vec()
+        }
+        words => words,
+    };
+    let mut best: Option<(Macro, usize)> = None;
+    for saved in macros {
+        let name: Vec<String> = saved
+            .name
+            .split(['-', '_'])
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        // Saved before names were checked
+        if name.is_empty() {
+            continue;
+        }
+        let joined = words
+            .first()
+            .is_some_and(|word| word.eq_ignore_ascii_case(&saved.name));
+        let length = if joined {
+            1
+        } else if words.len() >= name.len()
+            && name
+                .iter()
+                .zip(&words)
+                .all(|(part, word)| word.eq_ignore_ascii_case(part))
+        {
+            name.len()
+        } else {
+            continue;
+        };
+        // More words than parameters is a sentence that just starts like the name
+        if words.len() - length > saved.parameters().len() {
+            continue;
+        }
+        // The longest name wins, so "deploy" doesn't shadow "deploy-staging"
+        if best
+            .as_ref()
+            .is_none_or(|(best, _)| best.name.len() < saved.name.len())
+        {
+            best = Some((saved, length));
+        }
+    }
+    best.map(|(saved, length)| {
+        let arguments = words[length..]
+            .iter()
+            .map(|word| word.to_string())
+            .collect();
+        (saved, arguments)
+    })
+}
+
+pub fn format_macro(saved: &Macro) -> String {
+    let parameters = saved.parameters();
+    let parameters = if parameters.is_empty() {
+        String::new()
+    } else {
+        format!(
+            " {}",
+            parameters
+                .iter()
+                .map(|name| format!("<{}>", name))
+                .collect::<Vec<String>>()
+                .join(" ")
+        )
+    };
+    let host = saved
+        .host
+        .as_ref()
+        .map_or(String::new(), |host| format!(" on {}", host));
+    let steps = saved
+        .steps
+        .iter()
+        .map(|step| format!("`{}`", step))
+        .collect::<Vec<String>>()
+        .join(", then ");
+    format!("{}{}{}: {}", saved.name, parameters, host, steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved(name: &str, steps: &[&str]) -> Macro {
+        Macro {
+            name: name.to_string(),
+            chat_id: 1,
+            user_id: 1,
+            host: None,
+            steps: steps.iter().map(|step| step.to_string()).collect(),
+        }
+    }
+
+    fn arguments(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn parameters_are_listed_once_in_order() {
+        let deploy = saved(
+            "deploy",
+            &["git checkout {branch}", "make {target} BRANCH={branch}"],
+        );
+        assert_eq!(deploy.parameters(), vec!["branch", "target"]);
+    }
+
+    #[test]
+    fn expand_fills_positional_arguments() {
+        let deploy = saved("deploy", &["git checkout {branch}", "make {target}"]);
+        assert_eq!(
+            deploy.expand(&arguments(&["main", "release"])).unwrap(),
+            vec!["git checkout main", "make release"]
+        );
+    }
+
+    #[test]
+    fn expand_fills_named_arguments_first() {
+        let deploy = saved("deploy", &["git checkout {branch}", "make {target}"]);
+        assert_eq!(
+            deploy
+                .expand(&arguments(&["target=release", "main"]))
+                .unwrap(),
+            vec!["git checkout main", "make release"]
+        );
+        // An unknown name is a positional value that happens to contain "="
+        let env = saved("env", &["export {pair}"]);
+        assert_eq!(
+            env.expand(&arguments(&["A=1"])).unwrap(),
+            vec!["export A=1"]
+        );
+    }
+
+    #[test]
+    fn expand_reports_missing_parameters() {
+        let deploy = saved("deploy", &["git checkout {branch}", "make {target}"]);
+        let err = deploy.expand(&arguments(&["main"])).unwrap_err();
+        assert!(err.to_string().contains("needs target"));
+    }
+
+    #[test]
+    fn find_matches_names_and_takes_arguments() {
+        let macros = vec![
+            saved("deploy", &["make deploy"]),
+            saved("deploy-staging", &["git checkout {branch}"]),
+        ];
+        let (found, args) = find_in(macros.clone(), "deploy staging main").unwrap();
+        assert_eq!(found.name, "deploy-staging");
+        assert_eq!(args, vec!["main"]);
+        let (found, args) = find_in(macros.clone(), "/run DEPLOY-STAGING main").unwrap();
+        assert_eq!(found.name, "deploy-staging");
+        assert_eq!(args, vec!["main"]);
+        let (found, args) = find_in(macros.clone(), "deploy").unwrap();
+        assert_eq!(found.name, "deploy");
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn find_ignores_sentences_that_start_like_a_name() {
+        let macros = vec![saved("deploy", &["make deploy"])];
+        assert!(find_in(macros.clone(), "deploy the new version please").is_none());
+        assert!(find_in(macros, "how do I deploy").is_none());
+    }
+
+    #[test]
+    fn find_skips_names_without_words() {
+        // Saved before names needed a letter or digit
+        let macros = vec![saved("--", &["ls"])];
+        assert!(find_in(macros.clone(), "run").is_none());
+        assert!(find_in(macros, "/").is_none());
+    }
+}
@@ -3,113 +3,1164 @@ use std::collections::HashMap;
 use std::env;
 
 mod ai;
+mod audit;
+mod auth;
+mod backup;
+mod command;
+mod error;
+mod group;
+mod history;
+mod hosts;
+mod ingest;
+mod macros;
+mod metrics;
+mod migrate;
+mod plan;
 mod qdrant;
+mod render;
+mod risk;
+mod scheduler;
+mod storage;
+mod web;
 
+use crate::auth::{Capability, Role};
+use crate::error::BotError;
 use crate::qdrant::all_documents;
 use dotenv::dotenv;
+use serde_json::json;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use teloxide::net::Download;
 use teloxide::prelude::*;
-use teloxide::types::Message;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, Message};
+use teloxide::update_listeners::webhooks;
 use tokio::sync::Mutex;
+use tracing::Instrument;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
 
-    let _ = tokio::task::spawn_blocking(|| init_qdrant().and_then(|_| print_docs())).await?;
+    // `bot hash-password <password>` prints a value for BOT_PASSWORD_HASH
+    if env::args().nth(1).as_deref() == Some("hash-password") {
+        let password = env::args()
+            .nth(2)
+            .ok_or(anyhow::anyhow!("Usage: bot hash-password <password>"))?;
+        println!("{}", auth::hash_password(&password)?);
+        return Ok(());
+    }
+
+    init_tracing();
+
+    tokio::task::spawn_blocking(init_qdrant).await??;
+    let _ = tokio::task::spawn_blocking(log_memory_stats).await?;
+
+    if let Ok(addr) = env::var("METRICS_ADDR") {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(addr).await {
+                tracing::error!(error = %err, "Metrics endpoint failed");
+            }
+        });
+    }
 
     let bot = Bot::from_env();
+    let me = bot.get_me().await?;
+    let bot_id = me.id;
+    let bot_username = me.username().to_string();
+
+    tokio::spawn(scheduler::run(bot.clone()));
+
+    let user_states: UserStates = Arc::new(Mutex::new(HashMap::new()));
 
-    let user_states = Arc::new(Mutex::new(HashMap::<teloxide::types::ChatId, State>::new()));
+    let callback_handler = {
+        let user_states = user_states.clone();
+        move |query: CallbackQuery, bot: Bot| {
+            let user_states = user_states.clone();
+            let span = tracing::info_span!(
+                "callback",
+                chat_id = tracing::field::Empty,
+                user_id = query.from.id.0
+            );
+            async move { handle_callback(&bot, &user_states, &query).await }.instrument(span)
+        }
+    };
 
-    teloxide::repl(bot, move |message: Message, bot: Bot| {
+    let message_handler = move |message: Message, bot: Bot| {
         let user_states = user_states.clone();
+        let bot_username = bot_username.clone();
+        let span = tracing::info_span!(
+            "message",
+            chat_id = message.chat.id.0,
+            user_id = tracing::field::Empty
+        );
         async move {
+            let chat_id = message.chat.id;
+            let ctx = match Context::from_message(&bot, &message) {
+                Ok(ctx) => ctx,
+                Err(err) => {
+                    tracing::error!(error = format!("{:#}", err), "Unknown sender");
+                    let language = message
+                        .from
+                        .as_ref()
+                        .and_then(|user| user.language_code.clone())
+                        .unwrap_or_default();
+                    bot.send_message(chat_id, error::user_message(&err, &language))
+                        .await?;
+                    return respond(());
+                }
+            };
+            tracing::Span::current().record("user_id", ctx.user_id);
+            if group::is_group(&message) && !group::is_addressed(&message, bot_id, &bot_username) {
+                if let Some(text) = message.text() {
+                    learn_from_group(&user_states, &ctx, text).await;
+                }
+                return respond(());
+            }
+            if ctx.role.is_none() && auth::has_admin().unwrap_or(true) {
+                metrics::AUTH_FAILURES
+                    .with_label_values(&["not_invited"])
+                    .inc();
+                user_states.lock().await.remove(&ctx.session_key());
+                bot.send_message(
+                    chat_id,
+                    format!(
+                        "Access denied. Ask an admin to invite your Telegram ID {}.",
+                        ctx.user_id
+                    ),
+                )
+                .await?;
+                return respond(());
+            }
+            if expire_session(&user_states, &ctx).await {
+                bot.send_message(chat_id, "Session expired. Please enter the password.")
+                    .await?;
+                return respond(());
+            }
             if let Some(text) = message.text() {
-                let chat_id = message.chat.id;
-                let response_text = tokio::task::spawn_blocking({
-                    let input = text.to_owned();
-                    let user_states = user_states.clone();
-                    move || {
-                        let mut states = user_states.blocking_lock();
-                        let state = states.entry(chat_id).or_insert(State::AwaitingPassword);
-                        match State::process(&input, state) {
-                            Ok((new_state, output)) => {
-                                *state = new_state;
-                                output
+                let text = &group::strip_mention(text, &bot_username);
+                let awaiting_password = !is_authorized(&user_states, &ctx).await;
+                if text.starts_with('/') && !awaiting_password {
+                    handle_command(&bot, &user_states, &ctx, text).await?;
+                } else {
+                    let response_text = process_text(&user_states, &ctx, text.to_owned()).await;
+                    if awaiting_password {
+                        // Don't leave the password in the chat history, fails silently
+                        // if the bot has no rights to delete messages
+                        let _ = bot.delete_message(chat_id, message.id).await;
+                    }
+                    render::send(&bot, chat_id, &response_text).await?;
+                }
+            } else if let Some(document) = message.document() {
+                if !is_authorized(&user_states, &ctx).await {
+                    bot.send_message(chat_id, "Please enter the password first.")
+                        .await?;
+                    return respond(());
+                }
+                if let Err(err) = ctx.require(Capability::Remember) {
+                    render::send(&bot, chat_id, &ctx.error_reply(&err)).await?;
+                    return respond(());
+                }
+                let file_name = document
+                    .file_name
+                    .clone()
+                    .unwrap_or_else(|| "document.txt".to_string());
+                let importing = message
+                    .caption()
+                    .is_some_and(|caption| caption.starts_with("/import"))
+                    || matches!(
+                        user_states.lock().await.get(&ctx.session_key()),
+                        Some(Session {
+                            state: State::AwaitingImport,
+                            ..
+                        })
+                    );
+                if importing {
+                    set_state(&user_states, &ctx, State::Pending).await;
+                    let response_text = match download(&bot, &document.file.id).await {
+                        Ok(bytes) => {
+                            // Memory writes allocate IDs, so they run under the states lock
+                            let import_ctx = ctx.clone();
+                            process_with(&user_states, &ctx, move |_| {
+                                let (imported, skipped) = backup::import_json(&bytes)?;
+                                import_ctx.audit(
+                                    "import",
+                                    json!({ "imported": imported, "skipped": skipped }),
+                                );
+                                Ok((
+                                    State::Pending,
+                                    format!(
+                                        "Imported {} memories, skipped {} already stored.",
+                                        imported, skipped
+                                    ),
+                                ))
+                            })
+                            .await
+                        }
+                        Err(err) => ctx.error_reply(&err),
+                    };
+                    render::send(&bot, chat_id, &response_text).await?;
+                    return respond(());
+                }
+                let response_text = match download(&bot, &document.file.id).await {
+                    Ok(bytes) => tokio::task::spawn_blocking({
+                        let file_name = file_name.clone();
+                        let ctx = ctx.clone();
+                        let user_states = user_states.clone();
+                        move || {
+                            let result =
+                                ingest::file_chunks(&file_name, &bytes).and_then(|chunks| {
+                                    // Memory writes allocate IDs, so only they run under the states lock
+                                    let _states = user_states.blocking_lock();
+                                    ingest::save_chunks(
+                                        &chunks,
+                                        &json!({ "filename": file_name }),
+                                    )?;
+                                    Ok(chunks.len())
+                                });
+                            match result {
+                                Ok(count) => {
+                                    ctx.audit(
+                                        "ingest_file",
+                                        json!({ "filename": file_name, "chunks": count }),
+                                    );
+                                    format!(
+                                        "File \"{}\" saved to memory ({} parts).",
+                                        file_name, count
+                                    )
+                                }
+                                Err(err) => ctx.error_reply(&err),
                             }
-                            Err(err) => err.to_string(),
                         }
+                    })
+                    .await
+                    .unwrap_or_else(|err| ctx.error_reply(&err.into())),
+                    Err(err) => ctx.error_reply(&err),
+                };
+                render::send(&bot, chat_id, &response_text).await?;
+            } else if let Some((file_id, file_name)) = voice_file(&message) {
+                if !is_authorized(&user_states, &ctx).await {
+                    bot.send_message(chat_id, "Please enter the password first.")
+                        .await?;
+                    return respond(());
+                }
+                let transcript = match download(&bot, &file_id).await {
+                    Ok(bytes) => {
+                        tokio::task::spawn_blocking(move || ai::transcribe(&file_name, bytes))
+                            .await
+                            .unwrap_or_else(|err| Err(err.into()))
                     }
-                })
-                .await
-                .unwrap_or_else(|err| err.to_string());
-                bot.send_message(chat_id, response_text).await?;
+                    Err(err) => Err(err),
+                };
+                let response_text = match transcript {
+                    Ok(transcript) => {
+                        let response = process_text(&user_states, &ctx, transcript.clone()).await;
+                        format!("🎤 \"{}\"\n\n{}", transcript, response)
+                    }
+                    Err(err) => ctx.error_reply(&err),
+                };
+                render::send(&bot, chat_id, &response_text).await?;
+            } else if let Some(photo) = message.photo().and_then(|sizes| sizes.last()) {
+                if !is_authorized(&user_states, &ctx).await {
+                    bot.send_message(chat_id, "Please enter the password first.")
+                        .await?;
+                    return respond(());
+                }
+                let caption = message.caption().unwrap_or_default().to_string();
+                let response_text = match download(&bot, &photo.file.id).await {
+                    Ok(bytes) => {
+                        process_with(&user_states, &ctx, move |_| {
+                            State::new_photo(&bytes, &caption)
+                        })
+                        .await
+                    }
+                    Err(err) => ctx.error_reply(&err),
+                };
+                render::send(&bot, chat_id, &response_text).await?;
             } else {
-                bot.send_message(message.chat.id, "I did not understand what you said!")
+                bot.send_message(chat_id, "I did not understand what you said!")
                     .await?;
             }
             respond(())
         }
+        .instrument(span)
+    };
+
+    let handler = dptree::entry()
+        .branch(Update::filter_message().endpoint(message_handler))
+        .branch(Update::filter_callback_query().endpoint(callback_handler));
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
+        .default_handler(|_| async {})
+        .enable_ctrlc_handler()
+        .build();
+    match webhook_options()? {
+        Some(options) => {
+            tracing::info!(url = %options.url, addr = %options.address, "Starting in webhook mode");
+            let listener = webhooks::axum(bot, options).await?;
+            dispatcher
+                .dispatch_with_listener(
+                    listener,
+                    LoggingErrorHandler::with_custom_text("An error from the update listener"),
+                )
+                .await;
+        }
+        None => {
+            tracing::info!("Starting in long polling mode");
+            dispatcher.dispatch().await;
+        }
+    }
+    Ok(())
+}
+
+// WEBHOOK_URL switches from long polling to a webhook. The bot listens on WEBHOOK_ADDR over
+// plain HTTP, so HTTPS is expected to be terminated by a reverse proxy in front of it.
+fn webhook_options() -> anyhow::Result<Option<webhooks::Options>> {
+    let Ok(url) = env::var("WEBHOOK_URL") else {
+        return Ok(None);
+    };
+    let url = reqwest::Url::parse(&url)?;
+    let addr = env::var("WEBHOOK_ADDR")
+        .unwrap_or_else(|_| "0.0.0.0:8443".to_string())
+        .parse()?;
+    let mut options = webhooks::Options::new(addr, url);
+    // Telegram sends it in every request and the listener rejects requests without it.
+    // A random one is generated on each start when it is not set.
+    if let Ok(secret) = env::var("WEBHOOK_SECRET") {
+        if secret.is_empty()
+            || secret.len() > 256
+            || !secret
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(anyhow::anyhow!(
+                "WEBHOOK_SECRET must be 1-256 characters of A-Z, a-z, 0-9, _ and -"
+            ));
+        }
+        options = options.secret_token(secret);
+    }
+    // Public certificate, only needed when the proxy uses a self-signed one
+    if let Ok(path) = env::var("WEBHOOK_CERTIFICATE") {
+        options = options.certificate(InputFile::file(path));
+    }
+    Ok(Some(options))
+}
+
+// RUST_LOG sets levels (e.g. "info,ai_agent_telegram_bot=debug"), LOG_FORMAT=json for JSON lines.
+// Span close events carry the latency of every message, LLM call and Qdrant request.
+fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+    if env::var("LOG_FORMAT").is_ok_and(|format| format == "json") {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+// Slash commands that need more than a text reply, the rest go to the state machine
+async fn handle_command(
+    bot: &Bot,
+    user_states: &UserStates,
+    ctx: &Context,
+    text: &str,
+) -> ResponseResult<()> {
+    let chat_id = ctx.chat_id;
+    let mut parts = text.split_whitespace();
+    let command = parts.next().unwrap_or_default();
+    let argument = parts.next().unwrap_or_default();
+    let required = match command {
+        "/import" => Some(Capability::Remember),
+        "/jobs" | "/kill" | "/history" | "/macros" | "/addmacro" | "/removemacro" => {
+            Some(Capability::RunCommands)
+        }
+        "/migrate" | "/users" | "/invite" | "/revoke" | "/audit" | "/learning" | "/hosts"
+        | "/addhost" | "/removehost" => Some(Capability::ManageUsers),
+        _ => None,
+    };
+    if let Err(err) = required.map_or(Ok(()), |capability| ctx.require(capability)) {
+        render::send(bot, chat_id, &ctx.error_reply(&err)).await?;
+        return Ok(());
+    }
+    match command {
+        "/export" => {
+            let markdown = argument == "md";
+            let export = tokio::task::spawn_blocking(move || {
+                if markdown {
+                    backup::export_markdown()
+                } else {
+                    backup::export_json()
+                }
+            })
+            .await
+            .unwrap_or_else(|err| Err(err.into()));
+            match export {
+                Ok(bytes) => {
+                    let file_name = if markdown { "memory.md" } else { "memory.json" };
+                    bot.send_document(chat_id, InputFile::memory(bytes).file_name(file_name))
+                        .await?;
+                }
+                Err(err) => {
+                    render::send(bot, chat_id, &ctx.error_reply(&err)).await?;
+                }
+            }
+        }
+        "/import" => {
+            set_state(user_states, ctx, State::AwaitingImport).await;
+            bot.send_message(chat_id, "Send the exported memory.json file.")
+                .await?;
+        }
+        "/migrate" => {
+            bot.send_message(chat_id, "Re-embedding memory, this may take a while...")
+                .await?;
+            // Runs under the state lock, so nothing is saved to the old collection meanwhile
+            let ctx = ctx.clone();
+            let response_text = process_with(user_states, &ctx.clone(), move |_| {
+                let report = migrate::migrate()?;
+                ctx.audit("migrate", json!({ "report": report }));
+                Ok((State::Pending, report))
+            })
+            .await;
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/audit" => {
+            // /audit [limit] [user id or action]
+            let (limit, filter) = match argument.parse::<usize>() {
+                Ok(limit) => (limit, parts.next()),
+                Err(_) => (20, Some(argument).filter(|a| !a.is_empty())),
+            };
+            let response_text = match audit::query(limit, filter) {
+                Ok(entries) if entries.is_empty() => "Audit log is empty.".to_string(),
+                Ok(entries) => entries
+                    .iter()
+                    .map(audit::format_entry)
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                Err(err) => ctx.error_reply(&err),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/learning" => {
+            // /learning on|off, saving facts from every group message
+            let response_text = match (chat_id.is_user(), argument) {
+                (true, _) => "Learning works only in group chats.".to_string(),
+                (false, "on" | "off") => match group::set_learning(chat_id.0, argument == "on") {
+                    Ok(()) => {
+                        ctx.audit("learning", json!({ "enabled": argument == "on" }));
+                        if argument == "on" {
+                            "I will remember facts from this group's conversation.".to_string()
+                        } else {
+                            "I will only remember what I'm asked to.".to_string()
+                        }
+                    }
+                    Err(err) => ctx.error_reply(&err),
+                },
+                (false, _) => "Usage: /learning on|off".to_string(),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/schedule" | "/reminders" => {
+            let response_text = match scheduler::list(chat_id.0) {
+                Ok(jobs) if jobs.is_empty() => "Nothing is scheduled.".to_string(),
+                Ok(jobs) => jobs
+                    .iter()
+                    .map(scheduler::format_job)
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                Err(err) => ctx.error_reply(&err),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/cancel" => {
+            let response_text = match argument.parse::<u64>() {
+                Ok(id) => match scheduler::cancel(chat_id.0, id, ctx.owner_filter()) {
+                    Ok(true) => {
+                        ctx.audit("cancel", json!({ "job": id }));
+                        format!("Scheduled job {} cancelled.", id)
+                    }
+                    Ok(false) => format!("Scheduled job {} not found.", id),
+                    Err(err) => ctx.error_reply(&err),
+                },
+                Err(_) => "Usage: /cancel <job id>".to_string(),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/jobs" => {
+            let jobs = command::list(chat_id);
+            let response_text = if jobs.is_empty() {
+                "No running jobs.".to_string()
+            } else {
+                jobs.join("\n")
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/kill" => {
+            let response_text = match argument.parse::<u64>() {
+                Ok(id) => {
+                    if command::kill(chat_id, id, ctx.owner_filter()) {
+                        ctx.audit("kill", json!({ "job": id }));
+                        format!("Job {} is being stopped.", id)
+                    } else {
+                        format!("Job {} not found.", id)
+                    }
+                }
+                Err(_) => "Usage: /kill <job id>".to_string(),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/history" => {
+            // /history [limit], with a button to run each command again
+            let limit = argument.parse::<usize>().unwrap_or(10).clamp(1, 20);
+            match history::list(chat_id.0, limit) {
+                Ok(entries) if entries.is_empty() => {
+                    bot.send_message(chat_id, "No commands were run in this chat yet.")
+                        .await?;
+                }
+                Ok(entries) => {
+                    let text = entries
+                        .iter()
+                        .map(history::format_entry)
+                        .collect::<Vec<String>>()
+                        .join("\n");
+                    let buttons = entries
+                        .iter()
+                        .map(|entry| {
+                            InlineKeyboardButton::callback(
+                                format!("🔁 {}", entry.id),
+                                format!("rerun:{}", entry.id),
+                            )
+                        })
+                        .collect::<Vec<InlineKeyboardButton>>();
+                    let keyboard = InlineKeyboardMarkup::new(buttons.chunks(5).map(<[_]>::to_vec));
+                    render::send_with_keyboard(bot, chat_id, &text, Some(keyboard)).await?;
+                }
+                Err(err) => {
+                    render::send(bot, chat_id, &ctx.error_reply(&err)).await?;
+                }
+            }
+        }
+        "/hosts" => {
+            let response_text = match hosts::hosts() {
+                Ok(hosts) if hosts.is_empty() => "No hosts registered.".to_string(),
+                Ok(hosts) => hosts
+                    .iter()
+                    .map(|host| match &host.key {
+                        Some(key) => format!("{} {} (key {})", host.name, host.address, key),
+                        None => format!("{} {}", host.name, host.address),
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                Err(err) => ctx.error_reply(&err),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/addhost" => {
+            // /addhost <name> <user@address[:port]> [key path]
+            let response_text = match (argument, parts.next()) {
+                (name, Some(address)) if !name.is_empty() => {
+                    let host = hosts::Host {
+                        name: name.to_string(),
+                        address: address.to_string(),
+                        key: parts.next().map(str::to_string),
+                    };
+                    match hosts::add(host) {
+                        Ok(()) => {
+                            ctx.audit("add_host", json!({ "name": name, "address": address }));
+                            format!("Host {} added.", name)
+                        }
+                        Err(err) => ctx.error_reply(&err),
+                    }
+                }
+                _ => "Usage: /addhost <name> <user@address[:port]> [private key path]".to_string(),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/macros" => {
+            let response_text = match macros::list(chat_id.0) {
+                Ok(saved) if saved.is_empty() => "No macros in this chat.".to_string(),
+                Ok(saved) => saved
+                    .iter()
+                    .map(macros::format_macro)
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                Err(err) => ctx.error_reply(&err),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/addmacro" => {
+            let response_text = add_macro(ctx, text).unwrap_or_else(|err| ctx.error_reply(&err));
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/removemacro" => {
+            let response_text = match macros::remove(chat_id.0, argument, ctx.owner_filter()) {
+                Ok(true) => {
+                    ctx.audit("remove_macro", json!({ "name": argument }));
+                    format!("Macro {} removed.", argument)
+                }
+                Ok(false) => format!("Macro {} not found.", argument),
+                Err(err) => ctx.error_reply(&err),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/removehost" => {
+            let response_text = match hosts::remove(argument) {
+                Ok(true) => {
+                    ctx.audit("remove_host", json!({ "name": argument }));
+                    format!("Host {} removed.", argument)
+                }
+                Ok(false) => format!("Host {} not found.", argument),
+                Err(err) => ctx.error_reply(&err),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/users" => {
+            let response_text = match auth::users() {
+                Ok(users) if users.is_empty() => "No users yet.".to_string(),
+                Ok(users) => users
+                    .iter()
+                    .map(|user| format!("{} {} ({})", user.id, user.name, user.role))
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                Err(err) => ctx.error_reply(&err),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/invite" => {
+            let role = parts.next().map_or(Some(Role::Member), Role::parse);
+            let name = parts.collect::<Vec<&str>>().join(" ");
+            let response_text = match (argument.parse::<u64>(), role) {
+                (Ok(user_id), Some(role)) => {
+                    let user = auth::User {
+                        id: user_id,
+                        name,
+                        role,
+                    };
+                    match auth::invite(user) {
+                        Ok(()) => {
+                            ctx.audit(
+                                "invite",
+                                json!({ "user_id": user_id, "role": role.to_string() }),
+                            );
+                            format!("User {} invited as {}.", user_id, role)
+                        }
+                        Err(err) => ctx.error_reply(&err),
+                    }
+                }
+                _ => {
+                    "Usage: /invite <telegram user id> [admin|member|read-only] [name]".to_string()
+                }
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        "/revoke" => {
+            let response_text = match argument.parse::<u64>() {
+                Ok(user_id) => match auth::revoke(user_id) {
+                    Ok(true) => {
+                        ctx.audit("revoke", json!({ "user_id": user_id }));
+                        format!("Access for user {} revoked.", user_id)
+                    }
+                    Ok(false) => format!("User {} not found.", user_id),
+                    Err(err) => ctx.error_reply(&err),
+                },
+                Err(_) => "Usage: /revoke <telegram user id>".to_string(),
+            };
+            render::send(bot, chat_id, &response_text).await?;
+        }
+        _ => {
+            let response_text = process_text(user_states, ctx, text.to_owned()).await;
+            render::send(bot, chat_id, &response_text).await?;
+        }
+    }
+    Ok(())
+}
+
+// /addmacro <name>[@host] <command>, further commands on the next lines
+fn add_macro(ctx: &Context, text: &str) -> anyhow::Result<String> {
+    let mut lines = text.lines();
+    let first = lines.next().unwrap_or_default();
+    let rest = first
+        .trim_start()
+        .split_once(char::is_whitespace)
+        .map_or("", |(_, rest)| rest.trim());
+    let (target, first_step) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let steps: Vec<String> = std::iter::once(first_step)
+        .chain(lines)
+        .map(str::trim)
+        .filter(|step| !step.is_empty())
+        .map(str::to_string)
+        .collect();
+    if target.is_empty() || steps.is_empty() {
+        return Ok(
+            "Usage: /addmacro <name>[@host] <command>, more commands on the next lines. \
+                   {name} in a command is a parameter given when the macro runs."
+                .to_string(),
+        );
+    }
+    let (name, host) = match target.split_once('@') {
+        Some((name, host)) => {
+            let host = hosts::find(host)?
+                .ok_or_else(|| BotError::user(format!("Host {} is not registered.", host)))?;
+            (name, Some(host.name))
+        }
+        None => (target, None),
+    };
+    let saved = macros::Macro {
+        name: name.to_string(),
+        chat_id: ctx.chat_id.0,
+        user_id: ctx.user_id,
+        host,
+        steps,
+    };
+    let description = macros::format_macro(&saved);
+    macros::add(saved.clone())?;
+    ctx.audit(
+        "add_macro",
+        json!({ "name": saved.name, "host": saved.host, "steps": saved.steps }),
+    );
+    Ok(format!("Macro saved:\n{}", description))
+}
+
+// Re-run buttons of /history. The command goes through the usual confirmation again.
+async fn handle_callback(
+    bot: &Bot,
+    user_states: &UserStates,
+    query: &CallbackQuery,
+) -> ResponseResult<()> {
+    // Stops the loading indicator on the button
+    bot.answer_callback_query(query.id.clone()).await?;
+    let Some(chat_id) = query.message.as_ref().map(|message| message.chat().id) else {
+        return Ok(());
+    };
+    tracing::Span::current().record("chat_id", chat_id.0);
+    let Some(id) = query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix("rerun:"))
+        .and_then(|id| id.parse::<u64>().ok())
+    else {
+        return Ok(());
+    };
+    let ctx = match Context::new(bot, chat_id, &query.from) {
+        Ok(ctx) => ctx,
+        Err(err) => {
+            let language = query.from.language_code.clone().unwrap_or_default();
+            bot.send_message(chat_id, error::user_message(&err, &language))
+                .await?;
+            return Ok(());
+        }
+    };
+    if expire_session(user_states, &ctx).await {
+        bot.send_message(chat_id, "Session expired. Please enter the password.")
+            .await?;
+        return Ok(());
+    }
+    if !is_authorized(user_states, &ctx).await {
+        bot.send_message(chat_id, "Please enter the password first.")
+            .await?;
+        return Ok(());
+    }
+    let response_text = process_with(user_states, &ctx.clone(), move |_| {
+        ctx.require(Capability::RunCommands)?;
+        let entry = history::find(ctx.chat_id.0, id)?
+            .ok_or_else(|| BotError::user(format!("Command {} is not in the history.", id)))?;
+        State::confirm_command(&entry.command, entry.command.clone(), entry.host, None)
     })
     .await;
+    render::send(bot, chat_id, &response_text).await?;
     Ok(())
 }
 
+// Every user has their own session in every chat, so group members don't share one
+type SessionKey = (teloxide::types::ChatId, u64);
+
+type UserStates = Arc<Mutex<HashMap<SessionKey, Session>>>;
+
+struct Session {
+    state: State,
+    last_seen: Instant,
+}
+
+impl Session {
+    fn new(state: State) -> Session {
+        Session {
+            state,
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+// Inactive sessions go back to AwaitingPassword, SESSION_TIMEOUT_MINUTES=0 disables this
+fn session_timeout() -> Option<Duration> {
+    let minutes = env::var("SESSION_TIMEOUT_MINUTES")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(60);
+    (minutes > 0).then(|| Duration::from_secs(minutes * 60))
+}
+
+// Returns true if the user's session has just expired
+async fn expire_session(user_states: &UserStates, ctx: &Context) -> bool {
+    let mut states = user_states.lock().await;
+    let Some(session) = states.get_mut(&ctx.session_key()) else {
+        return false;
+    };
+    let expired = !matches!(session.state, State::AwaitingPassword)
+        && session_timeout().is_some_and(|timeout| session.last_seen.elapsed() > timeout);
+    if expired {
+        *session = Session::new(State::AwaitingPassword);
+        plan::cancel(ctx.chat_id, ctx.user_id);
+    } else {
+        session.last_seen = Instant::now();
+    }
+    expired
+}
+
+async fn set_state(user_states: &UserStates, ctx: &Context, state: State) {
+    user_states
+        .lock()
+        .await
+        .insert(ctx.session_key(), Session::new(state));
+}
+
+// Runs the input through the user's state machine and returns the reply
+async fn process_text(user_states: &UserStates, ctx: &Context, input: String) -> String {
+    let ctx = ctx.clone();
+    process_with(user_states, &ctx.clone(), move |state| {
+        State::process(&input, state, &ctx)
+    })
+    .await
+}
+
+// Runs a blocking transition of the user's state and returns the reply
+async fn process_with<F>(user_states: &UserStates, ctx: &Context, transition: F) -> String
+where
+    F: FnOnce(&State) -> anyhow::Result<(State, String)> + Send + 'static,
+{
+    let span = tracing::Span::current();
+    let key = ctx.session_key();
+    tokio::task::spawn_blocking({
+        let user_states = user_states.clone();
+        let ctx = ctx.clone();
+        move || {
+            let _enter = span.enter();
+            let mut states = user_states.blocking_lock();
+            let session = states
+                .entry(key)
+                .or_insert_with(|| Session::new(State::AwaitingPassword));
+            match transition(&session.state) {
+                Ok((new_state, output)) => {
+                    *session = Session::new(new_state);
+                    output
+                }
+                Err(err) => ctx.error_reply(&err),
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|err| ctx.error_reply(&err.into()))
+}
+
+async fn is_authorized(user_states: &UserStates, ctx: &Context) -> bool {
+    matches!(
+        user_states.lock().await.get(&ctx.session_key()),
+        Some(session) if !matches!(session.state, State::AwaitingPassword)
+    )
+}
+
+// Group messages that are not addressed to the bot, saved only where /learning is on.
+// Runs in the background, so the group never waits for it and never sees a reply.
+async fn learn_from_group(user_states: &UserStates, ctx: &Context, text: &str) {
+    if !ctx.role.is_some_and(|role| role.can(Capability::Remember)) {
+        return;
+    }
+    let span = tracing::Span::current();
+    let user_states = user_states.clone();
+    let ctx = ctx.clone();
+    let text = text.to_string();
+    tokio::task::spawn_blocking(move || {
+        let _enter = span.enter();
+        let result = group::is_learning(ctx.chat_id.0).and_then(|enabled| {
+            if !enabled {
+                return Ok(None);
+            }
+            let Some(fact) = group::extract_fact(&text, &ctx.user_name)? else {
+                return Ok(None);
+            };
+            // Memory writes allocate IDs, so they run under the states lock
+            let _states = user_states.blocking_lock();
+            group::save_fact(&fact, ctx.chat_id.0, ctx.user_id).map(Some)
+        });
+        match result {
+            Ok(Some(id)) => ctx.audit("learn", json!({ "id": id })),
+            Ok(None) => {}
+            Err(err) => tracing::error!(error = format!("{:#}", err), "Group learning failed"),
+        }
+    });
+}
+
+// Voice notes and audio files, both are sent for transcription
+fn voice_file(message: &Message) -> Option<(String, String)> {
+    if let Some(voice) = message.voice() {
+        Some((voice.file.id.clone(), "voice.ogg".to_string()))
+    } else {
+        message.audio().map(|audio| {
+            let file_name = audio
+                .file_name
+                .clone()
+                .unwrap_or_else(|| "audio.mp3".to_string());
+            (audio.file.id.clone(), file_name)
+        })
+    }
+}
+
+async fn download(bot: &Bot, file_id: &str) -> anyhow::Result<Vec<u8>> {
+    let file = bot.get_file(file_id).await?;
+    let mut bytes = Vec::new();
+    bot.download_file(&file.path, &mut bytes).await?;
+    Ok(bytes)
+}
+
+// Who sent the message. The role is looked up for every message, so revoking works at once.
+#[derive(Clone)]
+pub struct Context {
+    pub chat_id: teloxide::types::ChatId,
+    pub user_id: u64,
+    pub user_name: String,
+    pub role: Option<Role>,
+    // Telegram client's language, used for error messages
+    pub language: String,
+    // For replies that outlive the message handler, e.g. background commands
+    pub bot: Bot,
+}
+
+impl Context {
+    fn from_message(bot: &Bot, message: &Message) -> anyhow::Result<Context> {
+        let user = message
+            .from
+            .as_ref()
+            .ok_or_else(|| BotError::user("Messages without a sender are not supported"))?;
+        Context::new(bot, message.chat.id, user)
+    }
+
+    fn new(
+        bot: &Bot,
+        chat_id: teloxide::types::ChatId,
+        user: &teloxide::types::User,
+    ) -> anyhow::Result<Context> {
+        Ok(Context {
+            chat_id,
+            user_id: user.id.0,
+            user_name: user.full_name(),
+            role: auth::role_of(user.id.0)?,
+            language: user.language_code.clone().unwrap_or_default(),
+            bot: bot.clone(),
+        })
+    }
+
+    fn session_key(&self) -> SessionKey {
+        (self.chat_id, self.user_id)
+    }
+
+    // A broken audit log must not break the action itself, so errors only go to stderr
+    pub fn audit(&self, action: &str, details: serde_json::Value) {
+        let result = audit::record(
+            self.user_id,
+            &self.user_name,
+            self.chat_id.0,
+            action,
+            details,
+        );
+        if let Err(err) = result {
+            tracing::error!(error = %err, "Audit log error");
+        }
+    }
+
+    // The full error goes to the log, the user gets a friendly message
+    pub fn error_reply(&self, err: &anyhow::Error) -> String {
+        if !matches!(err.downcast_ref::<BotError>(), Some(BotError::User(_))) {
+            tracing::error!(error = format!("{:#}", err), "Request failed");
+        }
+        error::user_message(err, &self.language)
+    }
+
+    // Owner to limit jobs and macros to: admins can manage anyone's, others only their own
+    pub fn owner_filter(&self) -> Option<u64> {
+        if self
+            .role
+            .is_some_and(|role| role.can(Capability::ManageUsers))
+        {
+            None
+        } else {
+            Some(self.user_id)
+        }
+    }
+
+    pub fn require(&self, capability: Capability) -> anyhow::Result<()> {
+        match self.role {
+            Some(role) if role.can(capability) => Ok(()),
+            Some(role) => Err(BotError::user(format!(
+                "Your role ({}) is not allowed to {}.",
+                role, capability
+            ))),
+            None => Err(BotError::user(format!(
+                "You are not allowed to {}.",
+                capability
+            ))),
+        }
+    }
+}
+
 enum State {
     AwaitingPassword,
     Pending,
-    ConfirmForget { info: String },
-    ConfirmCommand { message: String, command: String },
+    AwaitingImport,
+    ConfirmForget {
+        info: String,
+    },
+    ConfirmRemember {
+        info: String,
+    },
+    ConfirmCommand {
+        message: String,
+        command: String,
+        // Registered host to run it on over SSH, locally if None
+        host: Option<String>,
+        // Cron expression when the command should run on a schedule instead of now
+        schedule: Option<String>,
+    },
+    // The steps live in `plan`, so finished steps can move the plan on in the background
+    ConfirmPlan {
+        message: String,
+    },
 }
 
 impl State {
-    pub fn process(input: &str, state: &State) -> anyhow::Result<(Self, String)> {
+    pub fn process(input: &str, state: &State, ctx: &Context) -> anyhow::Result<(Self, String)> {
         match state {
-            State::AwaitingPassword => State::process_password(input),
-            State::Pending => State::exec_pending(input),
-            State::ConfirmForget { info } => State::exec_forget(input, info),
-            State::ConfirmCommand { command, message } => {
-                State::exec_confirm_command(input, command, message)
-            }
+            State::AwaitingPassword => State::process_password(input, ctx),
+            State::Pending | State::AwaitingImport => State::exec_pending(input, ctx),
+            State::ConfirmForget { info } => State::exec_forget(input, info, ctx),
+            State::ConfirmRemember { info } => State::exec_confirm_remember(input, info, ctx),
+            State::ConfirmCommand {
+                command,
+                message,
+                host,
+                schedule,
+            } => State::exec_confirm_command(
+                input,
+                command,
+                host.as_deref(),
+                schedule.as_deref(),
+                message,
+                ctx,
+            ),
+            State::ConfirmPlan { message } => State::exec_confirm_plan(input, message, ctx),
         }
     }
 
-    pub fn process_password(input: &str) -> anyhow::Result<(Self, String)> {
-        let correct_password = env::var("BOT_PASSWORD")?;
-        if input.trim() == correct_password {
+    pub fn process_password(input: &str, ctx: &Context) -> anyhow::Result<(Self, String)> {
+        if let Some(remaining) = auth::lockout_remaining(ctx.chat_id.0, ctx.user_id) {
+            metrics::AUTH_FAILURES.with_label_values(&["locked"]).inc();
+            return Ok((
+                State::AwaitingPassword,
+                format!(
+                    "Too many incorrect attempts. Try again in {} min.",
+                    auth::lockout_minutes(remaining)
+                ),
+            ));
+        }
+        if auth::verify_password(input.trim())? {
+            auth::reset_failures(ctx.chat_id.0, ctx.user_id);
+            if ctx.role.is_none() {
+                auth::invite(auth::User {
+                    id: ctx.user_id,
+                    name: ctx.user_name.clone(),
+                    role: Role::Admin,
+                })?;
+                return Ok((
+                    State::Pending,
+                    "Password accepted. There were no admins, so you are the admin now.\n\
+                     Use /invite to give access to other users."
+                        .to_string(),
+                ));
+            }
             Ok((
                 State::Pending,
                 "Password accepted. You may continue using the bot.".to_string(),
             ))
         } else {
-            Ok((
-                State::AwaitingPassword,
-                "Incorrect password. Please try again.".to_string(),
-            ))
+            metrics::AUTH_FAILURES
+                .with_label_values(&["password"])
+                .inc();
+            match auth::record_failure(ctx.chat_id.0, ctx.user_id) {
+                Some(lockout) => Ok((
+                    State::AwaitingPassword,
+                    format!(
+                        "Too many incorrect attempts. Try again in {} min.",
+                        auth::lockout_minutes(lockout)
+                    ),
+                )),
+                None => Ok((
+                    State::AwaitingPassword,
+                    "Incorrect password. Please try again.".to_string(),
+                )),
+            }
         }
     }
 
-    pub fn exec_pending(message: &str) -> anyhow::Result<(Self, String)> {
+    pub fn exec_pending(message: &str, ctx: &Context) -> anyhow::Result<(Self, String)> {
+        // Macros are matched by name, without asking the LLM
+        if ctx.require(Capability::RunCommands).is_ok() {
+            if let Some((saved, arguments)) = macros::find(ctx.chat_id.0, message)? {
+                tracing::info!(name = saved.name, "Macro matched");
+                metrics::MESSAGES.with_label_values(&["macro"]).inc();
+                return State::new_macro(saved, &arguments, ctx);
+            }
+        }
         let user = format!(
             "<user_message>{}</user_message> Inside user_message there is: \n \
         1. a question (interrogative sentence) \n \
         2. affirmative information, data, facts or details \n \
         3. a sentence requesting to delete information from memory \n \
         4. a terminal command \n \
-        5. other \n \
+        5. a link to a web page to remember \n \
+        6. a request to remind about something at some time \n \
+        7. other \n \
         Respond with a number. ",
             message
         );
         let response = ai::llm("Give a short answer without explanations or details", &user)?;
-        // If there is a parsing error, return 5.
-        let number = State::extract_number(&response).parse::<i32>().unwrap_or(5);
+        // If there is a parsing error, return 7.
+        let number = State::extract_number(&response).parse::<i32>().unwrap_or(7);
+        tracing::info!(intent = number, "Message classified");
+        let intent = match number {
+            1 => "question",
+            2 => "remember",
+            3 => "forget",
+            4 => "command",
+            5 => "url",
+            6 => "reminder",
+            _ => "chat",
+        };
+        metrics::MESSAGES.with_label_values(&[intent]).inc();
 
         match number {
             1 => State::exec_answer(message),
-            2 => State::exec_remember(message),
-            3 => State::new_forget(message),
-            4 => State::new_command(message),
+            2 => ctx
+                .require(Capability::Remember)
+                .and_then(|_| State::exec_remember(message, ctx)),
+            3 => ctx
+                .require(Capability::Forget)
+                .and_then(|_| State::new_forget(message)),
+            4 => ctx
+                .require(Capability::RunCommands)
+                .and_then(|_| State::new_command(message, ctx)),
+            5 => ctx
+                .require(Capability::Remember)
+                .and_then(|_| State::exec_remember_url(message, ctx)),
+            6 => State::exec_remind(message, ctx),
             _ => State::exec_chat(message),
         }
     }
@@ -121,12 +1172,14 @@ impl State {
             message
         );
         let response = ai::llm("Give a short answer without explanations or details", &user)?;
-        let keywords = State::extract_tag(&response, "keywords");
+        let keywords = ai::extract_tag(&response, "keywords");
         let docs = qdrant::search_smart(&keywords)?;
-        println!("Question: {}", message);
-        for doc in &docs {
-            println!("{}: {}", doc.distance, doc.text);
-        }
+        // Only ids and scores, memory contents stay out of the logs
+        tracing::debug!(
+            ids = ?docs.iter().map(|doc| doc.id).collect::<Vec<i32>>(),
+            scores = ?docs.iter().map(|doc| doc.distance).collect::<Vec<f32>>(),
+            "Documents found"
+        );
         let docs_text = docs
             .iter()
             .map(|doc| doc.text.clone())
@@ -140,16 +1193,6 @@ impl State {
         Ok((State::Pending, response))
     }
 
-    fn extract_tag(input: &str, tag: &str) -> String {
-        let re = Regex::new(&format!(r"(?i)<{}>(.*?)</{}>", tag, tag)).unwrap();
-        if let Some(caps) = re.captures(input) {
-            caps.get(1)
-                .map_or(String::new(), |m| m.as_str().to_string())
-        } else {
-            String::new()
-        }
-    }
-
     fn extract_number(input: &str) -> String {
         let re = Regex::new(r"\d+").unwrap();
         match re.find(input) {
@@ -167,11 +1210,104 @@ impl State {
         Ok((State::Pending, response))
     }
 
-    pub fn exec_remember(message: &str) -> anyhow::Result<(Self, String)> {
+    pub fn exec_remember(message: &str, ctx: &Context) -> anyhow::Result<(Self, String)> {
         let mut last_document_id = qdrant::last_document_id()?;
         last_document_id += 1;
         qdrant::add_document(last_document_id, message)?;
-        Ok((State::Pending, "Information saved.".to_string()))
+        ctx.audit(
+            "remember",
+            json!({ "id": last_document_id, "length": message.chars().count() }),
+        );
+        // Facts with dates (birthdays, deadlines) also get a reminder, the fact is saved anyway
+        let reminder = State::schedule_reminder(message, ctx).unwrap_or_else(|err| {
+            tracing::error!(error = format!("{:#}", err), "Reminder extraction failed");
+            None
+        });
+        match reminder {
+            Some(job) => Ok((
+                State::Pending,
+                format!(
+                    "Information saved. I'll remind you:\n{}",
+                    scheduler::format_job(&job)
+                ),
+            )),
+            None => Ok((State::Pending, "Information saved.".to_string())),
+        }
+    }
+
+    pub fn exec_remind(message: &str, ctx: &Context) -> anyhow::Result<(Self, String)> {
+        match State::schedule_reminder(message, ctx)? {
+            Some(job) => Ok((
+                State::Pending,
+                format!("I'll remind you:\n{}", scheduler::format_job(&job)),
+            )),
+            None => Ok((
+                State::Pending,
+                "I couldn't tell when to remind you. Please give a date or time.".to_string(),
+            )),
+        }
+    }
+
+    fn schedule_reminder(message: &str, ctx: &Context) -> anyhow::Result<Option<scheduler::Job>> {
+        let Some((at, repeat, text)) = scheduler::extract(message)? else {
+            return Ok(None);
+        };
+        let job = scheduler::add(
+            ctx.chat_id.0,
+            ctx.user_id,
+            at,
+            repeat,
+            scheduler::Action::Remind { text },
+        )?;
+        ctx.audit("schedule", json!({ "job": job.id, "at": job.at }));
+        Ok(Some(job))
+    }
+
+    pub fn exec_remember_url(message: &str, ctx: &Context) -> anyhow::Result<(Self, String)> {
+        match web::find_url(message) {
+            Some(url) => {
+                let (title, summary) = web::remember_url(&url)?;
+                ctx.audit("remember_url", json!({ "url": url, "title": title }));
+                Ok((
+                    State::Pending,
+                    format!("Page \"{}\" saved.\n\n{}", title, summary),
+                ))
+            }
+            None => State::exec_remember(message, ctx),
+        }
+    }
+
+    pub fn new_photo(image: &[u8], caption: &str) -> anyhow::Result<(Self, String)> {
+        let user = format!(
+            "<user_caption>{}</user_caption> Describe the image in a few sentences. \
+         If there is any text on it (receipt, sign, sticker, document), \
+         write out all of the text exactly. Follow the instructions from user_caption if there are any.",
+            caption
+        );
+        let description = ai::llm_vision(
+            "Give a short answer without greetings or explanations",
+            &user,
+            image,
+        )?;
+        Ok((
+            State::ConfirmRemember {
+                info: description.clone(),
+            },
+            format!("{}\n\nSave this to memory?", description),
+        ))
+    }
+
+    pub fn exec_confirm_remember(
+        message: &str,
+        info: &str,
+        ctx: &Context,
+    ) -> anyhow::Result<(Self, String)> {
+        if State::is_condition(message, "consent")? {
+            ctx.require(Capability::Remember)?;
+            State::exec_remember(info, ctx)
+        } else {
+            Ok((State::Pending, "Information not saved.".to_string()))
+        }
     }
 
     pub fn new_forget(message: &str) -> anyhow::Result<(Self, String)> {
@@ -181,7 +1317,7 @@ impl State {
             message
         );
         let response = ai::llm("Give a short answer without explanations or details", &user)?;
-        let keywords = State::extract_tag(&response, "keywords");
+        let keywords = ai::extract_tag(&response, "keywords");
         let doc = qdrant::search_one(&keywords)?;
         let text = doc.text.clone();
         Ok((
@@ -190,64 +1326,234 @@ impl State {
         ))
     }
 
-    pub fn exec_forget(message: &str, info: &str) -> anyhow::Result<(Self, String)> {
+    pub fn exec_forget(message: &str, info: &str, ctx: &Context) -> anyhow::Result<(Self, String)> {
         if State::is_condition(message, "consent")? {
+            ctx.require(Capability::Forget)?;
             let doc = qdrant::search_one(info)?;
             qdrant::delete_document(doc.id)?;
+            ctx.audit(
+                "forget",
+                json!({ "id": doc.id, "length": doc.text.chars().count() }),
+            );
             Ok((State::Pending, "Information forgotten.".to_string()))
         } else {
             Ok((State::Pending, "Information not forgotten.".to_string()))
         }
     }
 
-    pub fn new_command(message: &str) -> anyhow::Result<(Self, String)> {
+    pub fn new_command(message: &str, ctx: &Context) -> anyhow::Result<(Self, String)> {
+        let host_names = hosts::hosts()?
+            .into_iter()
+            .map(|host| host.name)
+            .collect::<Vec<String>>();
+        let hosts_prompt = if host_names.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "If the user_request names one of the hosts {}, also add <host>NAME</host>. ",
+                host_names.join(", ")
+            )
+        };
         let user = format!(
             "<user_request>{}</user_request> Based on the user_request description, I will form a Linux command for the terminal. \
-             Respond in the format <command>COMMAND</command>",
-            message
+             Respond in the format <command>COMMAND</command>. If it takes several commands that depend on \
+             each other's results, respond with each one in its own <command> tag, in order. {}\
+             If the user_request asks to run it regularly (e.g. every morning), also add \
+             <cron>MINUTE HOUR DAY_OF_MONTH MONTH DAY_OF_WEEK</cron> with weekday names like MON-FRI",
+            message, hosts_prompt
         );
         let response = ai::llm("Give a short answer without explanations or details", &user)?;
-        let command = State::extract_tag(&response, "command");
+        let commands = ai::extract_tags(&response, "command");
+        let host = Some(ai::extract_tag(&response, "host")).and_then(|name| {
+            host_names
+                .into_iter()
+                .find(|host| host.eq_ignore_ascii_case(&name))
+        });
+        // A schedule the user asked for is never dropped silently
+        let schedule = match ai::extract_tag(&response, "cron").as_str() {
+            "" => None,
+            cron => {
+                scheduler::parse_cron(cron)?;
+                Some(cron.to_string())
+            }
+        };
+        if commands.len() > 1 && schedule.is_none() {
+            let question = plan::propose(ctx.chat_id, ctx.user_id, message, host, commands, true);
+            return Ok((
+                State::ConfirmPlan {
+                    message: message.to_string(),
+                },
+                question,
+            ));
+        }
+        // A scheduled run can't be confirmed step by step, it stops at the first failure instead
+        State::confirm_command(message, commands.join(" && "), host, schedule)
+    }
+
+    // A macro is a fixed sequence, so its plan is not revised between the steps
+    pub fn new_macro(
+        saved: macros::Macro,
+        arguments: &[String],
+        ctx: &Context,
+    ) -> anyhow::Result<(Self, String)> {
+        let mut steps = saved.expand(arguments)?;
+        if steps.len() == 1 {
+            return State::confirm_command(&saved.name, steps.remove(0), saved.host, None);
+        }
+        let question = plan::propose(
+            ctx.chat_id,
+            ctx.user_id,
+            &saved.name,
+            saved.host,
+            steps,
+            false,
+        );
+        Ok((
+            State::ConfirmPlan {
+                message: saved.name,
+            },
+            question,
+        ))
+    }
+
+    // Asks to confirm the command, with its explanation and risk
+    fn confirm_command(
+        message: &str,
+        command: String,
+        host: Option<String>,
+        schedule: Option<String>,
+    ) -> anyhow::Result<(Self, String)> {
+        let target = host
+            .as_ref()
+            .map_or(String::new(), |host| format!(" on {}", host));
+        let prompt = match &schedule {
+            Some(cron) => format!(
+                "Run command \"{}\"{} on schedule \"{}\"?",
+                command, target, cron
+            ),
+            None => format!("Run command \"{}\"{}?", command, target),
+        };
+        // Without the LLM the static rating is still shown
+        let (explanation, risk) = risk::assess(&command).unwrap_or_else(|err| {
+            tracing::error!(error = format!("{:#}", err), "Command analysis failed");
+            (String::new(), risk::static_risk(&command))
+        });
+        let question = if explanation.is_empty() {
+            format!("Risk: {}\n\n{}", risk, prompt)
+        } else {
+            format!("{}\nRisk: {}\n\n{}", explanation, risk, prompt)
+        };
         Ok((
             State::ConfirmCommand {
-                command: command.clone(),
+                command,
                 message: message.to_string(),
+                host,
+                schedule,
             },
-            format!("Run command \"{}\"?", command),
+            question,
         ))
     }
 
     pub fn exec_confirm_command(
         message: &str,
         command: &str,
+        host: Option<&str>,
+        schedule: Option<&str>,
         priv_message: &str,
+        ctx: &Context,
     ) -> anyhow::Result<(Self, String)> {
         if State::is_condition(message, "yes")? {
-            let output = std::process::Command::new("sh")
-                .arg("-c")
-                .arg(command)
-                .output();
-            match output {
-                Ok(result) => {
-                    let stdout = String::from_utf8_lossy(&result.stdout);
-                    let stderr = String::from_utf8_lossy(&result.stderr);
-                    let mut ret = format!("Command execution result\n```\n{}\n```", stdout);
-                    if !stderr.is_empty() {
-                        ret = format!("Errors during command execution\n```\n{}\n```", stderr);
-                    }
-                    Ok((State::Pending, ret))
-                }
-                Err(e) => Ok((State::Pending, format!("Error executing command: {}", e))),
+            ctx.require(Capability::RunCommands)?;
+            // The host could have been removed while waiting for the confirmation
+            let target = match host {
+                Some(name) => Some(hosts::find(name)?.ok_or_else(|| {
+                    BotError::user(format!("Host {} is not registered anymore.", name))
+                })?),
+                None => None,
+            };
+            if let Some(cron) = schedule {
+                let repeat = scheduler::Repeat::Cron(cron.to_string());
+                let now = chrono::Utc::now();
+                let at = scheduler::next_after(now, &repeat, now)
+                    .ok_or_else(|| BotError::user("This schedule never runs."))?;
+                let job = scheduler::add(
+                    ctx.chat_id.0,
+                    ctx.user_id,
+                    at,
+                    repeat,
+                    scheduler::Action::Command {
+                        command: command.to_string(),
+                        host: host.map(str::to_string),
+                    },
+                )?;
+                ctx.audit(
+                    "schedule",
+                    json!({ "job": job.id, "command": command, "host": host, "cron": cron }),
+                );
+                return Ok((
+                    State::Pending,
+                    format!("Command scheduled:\n{}", scheduler::format_job(&job)),
+                ));
             }
+            // Runs in the background, so long commands don't block the chat
+            let id = command::start(
+                ctx.bot.clone(),
+                ctx.chat_id,
+                ctx.user_id,
+                &ctx.user_name,
+                target,
+                command,
+            );
+            Ok((
+                State::Pending,
+                format!("Job {} started, /kill {} stops it.", id, id),
+            ))
         } else if message.len() > 7 {
             let message = format!("{}\n{}", priv_message, message);
-            State::new_command(&message)
+            State::new_command(&message, ctx)
         } else {
-            println!("Command not executed.");
             Ok((State::Pending, "Command not executed.".to_string()))
         }
     }
 
+    pub fn exec_confirm_plan(
+        message: &str,
+        priv_message: &str,
+        ctx: &Context,
+    ) -> anyhow::Result<(Self, String)> {
+        // Finished or stopped in the background
+        if !plan::is_active(ctx.chat_id, ctx.user_id) {
+            return State::exec_pending(message, ctx);
+        }
+        let all = message
+            .trim()
+            .trim_end_matches(['.', '!'])
+            .eq_ignore_ascii_case("all");
+        if all || State::is_condition(message, "yes")? {
+            ctx.require(Capability::RunCommands)?;
+            let reply = plan::confirm(
+                ctx.bot.clone(),
+                ctx.chat_id,
+                ctx.user_id,
+                &ctx.user_name,
+                all,
+            )?;
+            Ok((
+                State::ConfirmPlan {
+                    message: priv_message.to_string(),
+                },
+                reply,
+            ))
+        } else if message.len() > 7 {
+            plan::cancel(ctx.chat_id, ctx.user_id);
+            let message = format!("{}\n{}", priv_message, message);
+            State::new_command(&message, ctx)
+        } else {
+            plan::cancel(ctx.chat_id, ctx.user_id);
+            Ok((State::Pending, "Plan stopped.".to_string()))
+        }
+    }
+
     pub fn is_condition(message: &str, condition: &str) -> anyhow::Result<bool> {
         let user = format!(
             "<user_request>{}</user_request> Does user_request contain {}? \
@@ -260,17 +1566,42 @@ impl State {
 }
 
 fn init_qdrant() -> anyhow::Result<()> {
+    let size = ai::emb("dimension probe")?.len();
     if !qdrant::exists_collection()? {
-        qdrant::create_collection()?;
+        if let Some(collection) = migrate::recover_alias()? {
+            tracing::warn!(collection, "Alias restored");
+        } else {
+            let expected: usize = env::var("EMBEDDINGS_LENGTH")?.parse()?;
+            if expected != size {
+                return Err(anyhow::anyhow!(
+                    "EMBEDDINGS_LENGTH is {} but {} returns {} dimensions, fix EMBEDDINGS_LENGTH in .env",
+                    expected,
+                    env::var("EMBEDDINGS_MODEL")?,
+                    size
+                ));
+            }
+            qdrant::create_collection()?;
+        }
+    } else if migrate::adopt_alias()? {
+        tracing::info!("Collection moved behind an alias");
+    }
+    if let Some(problem) = migrate::check_schema(size)? {
+        if env::var("AUTO_MIGRATE").is_ok_and(|v| v == "true") {
+            tracing::warn!("{} Migrating...", problem);
+            tracing::info!("{}", migrate::migrate()?);
+        } else {
+            return Err(anyhow::anyhow!(
+                "{} Set AUTO_MIGRATE=true to re-embed the memory on start, \
+                 or restore the previous EMBEDDINGS_MODEL and run /migrate later.",
+                problem
+            ));
+        }
     }
     Ok(())
 }
 
-fn print_docs() -> anyhow::Result<()> {
-    // Print bot's memory contents
+fn log_memory_stats() -> anyhow::Result<()> {
     let docs = all_documents()?;
-    for doc in docs {
-        println!("{}", doc.text);
-    }
+    tracing::info!(documents = docs.len(), "Memory loaded");
     Ok(())
 }
@@ -0,0 +1,193 @@
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, Encoder, Histogram,
+    HistogramVec, IntCounterVec, TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+// Longer request headers are not from Prometheus
+const MAX_REQUEST_BYTES: usize = 8192;
+
+pub static MESSAGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "bot_messages_total",
+        "Messages by classified intent",
+        &["intent"]
+    )
+    .unwrap()
+});
+
+pub static LLM_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "bot_llm_request_duration_seconds",
+        "LLM request latency",
+        &["kind"],
+        vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+    )
+    .unwrap()
+});
+
+pub static LLM_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("bot_llm_errors_total", "Failed LLM requests", &["kind"]).unwrap()
+});
+
+pub static EMBEDDING_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "bot_embedding_duration_seconds",
+        "Embedding request latency",
+        &["model"],
+        vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+    )
+    .unwrap()
+});
+
+pub static EMBEDDING_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "bot_embedding_errors_total",
+        "Failed embedding requests",
+        &["model"]
+    )
+    .unwrap()
+});
+
+pub static SEARCH_TOP_SCORE: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "bot_search_top_score",
+        "Score of the best Qdrant search result",
+        vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0]
+    )
+    .unwrap()
+});
+
+pub static COMMANDS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "bot_commands_total",
+        "Executed terminal commands by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static AUTH_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "bot_auth_failures_total",
+        "Rejected access attempts by reason",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+// Observes the latency of a request and counts it as an error if it fails
+pub fn timed<T>(
+    seconds: &HistogramVec,
+    errors: &IntCounterVec,
+    label: &str,
+    request: impl FnOnce() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let start = Instant::now();
+    let result = request();
+    seconds
+        .with_label_values(&[label])
+        .observe(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        errors.with_label_values(&[label]).inc();
+    }
+    result
+}
+
+// Minimal HTTP endpoint, METRICS_ADDR enables it, e.g. 0.0.0.0:9090
+pub async fn serve(addr: String) -> anyhow::Result<()> {
+    // Registers every metric up front, so they are exported before the first observation
+    LazyLock::force(&MESSAGES);
+    LazyLock::force(&LLM_SECONDS);
+    LazyLock::force(&LLM_ERRORS);
+    LazyLock::force(&EMBEDDING_SECONDS);
+    LazyLock::force(&EMBEDDING_ERRORS);
+    LazyLock::force(&SEARCH_TOP_SCORE);
+    LazyLock::force(&COMMANDS);
+    LazyLock::force(&AUTH_FAILURES);
+    let listener = TcpListener::bind(&addr).await?;
+    tracing::info!(addr, "Metrics endpoint started");
+    loop {
+        // Errors like running out of file descriptors pass, the endpoint must stay up
+        let mut socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(err) => {
+                tracing::error!(error = %err, "Metrics connection not accepted");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            let read = tokio::time::timeout(Duration::from_secs(5), read_request(&mut socket));
+            let Ok(Ok(request)) = read.await else {
+                return;
+            };
+            let response = if request.starts_with("GET /metrics ") {
+                let mut body = Vec::new();
+                let encoder = TextEncoder::new();
+                if let Err(err) = encoder.encode(&prometheus::gather(), &mut body) {
+                    tracing::error!(error = %err, "Metrics encoding failed");
+                }
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    encoder.format_type(),
+                    body.len()
+                )
+                .into_bytes();
+                response.extend(body);
+                response
+            } else {
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+            };
+            let _ = socket.write_all(&response).await;
+        });
+    }
+}
+
+// Reads until the end of the request headers, they can arrive in several pieces
+async fn read_request(socket: &mut (impl AsyncRead + Unpin)) -> std::io::Result<String> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_request_waits_for_all_pieces() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            for piece in ["GET /met", "rics HTTP/1.1\r\nHost: x\r\n", "\r\n"] {
+                client.write_all(piece.as_bytes()).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        let request = read_request(&mut server).await.unwrap();
+        assert!(request.starts_with("GET /metrics "));
+        assert!(request.ends_with("\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn read_request_stops_at_the_size_limit() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let header = "X".repeat(1000);
+            while client.write_all(header.as_bytes()).await.is_ok() {}
+        });
+        let request = read_request(&mut server).await.unwrap();
+        assert!(request.len() >= MAX_REQUEST_BYTES);
+        assert!(request.len() < MAX_REQUEST_BYTES + 1024);
+    }
+}
@@ -0,0 +1,157 @@
+use std::env;
+
+use crate::ai;
+use crate::qdrant;
+
+// Compares the stored collection config with the actual embedding size.
+// Returns a description of the mismatch, None if everything fits.
+pub fn check_schema(size: usize) -> anyhow::Result<Option<String>> {
+    let alias = env::var("QDRANT_COLLECTION_NAME")?;
+    let current = qdrant::alias_target(&alias)?.unwrap_or(alias);
+    let config = qdrant::collection_config(&current)?;
+    let mut problems = Vec::new();
+    if config.size != size {
+        problems.push(format!(
+            "Collection \"{}\" stores {}-dimensional vectors, but {} returns {} dimensions.",
+            current,
+            config.size,
+            env::var("EMBEDDINGS_MODEL")?,
+            size
+        ));
+    }
+    if config.distance != "Cosine" {
+        problems.push(format!(
+            "Collection \"{}\" uses {} distance instead of Cosine.",
+            current, config.distance
+        ));
+    }
+    if problems.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(problems.join(" ")))
+    }
+}
+
+// Collections created before QDRANT_COLLECTION_NAME became an alias are moved behind one.
+// Runs on start before any message is handled, so nobody reads the collection meanwhile.
+// Returns false if it already is an alias.
+pub fn adopt_alias() -> anyhow::Result<bool> {
+    let alias = env::var("QDRANT_COLLECTION_NAME")?;
+    if qdrant::alias_target(&alias)?.is_some() {
+        return Ok(false);
+    }
+    let config = qdrant::collection_config(&alias)?;
+    let new_collection = qdrant::versioned_name(&alias)?;
+    qdrant::create_collection_with_size(&new_collection, config.size)?;
+    if let Err(err) = qdrant::copy_points(&alias, &new_collection) {
+        let _ = qdrant::delete_collection(&new_collection);
+        return Err(err);
+    }
+    // An alias can't share its name with a collection. Everything is already copied, if the
+    // alias isn't created now `recover_alias` finds the copy on the next start.
+    qdrant::delete_collection(&alias)?;
+    if let Err(err) = qdrant::switch_alias(&alias, &new_collection) {
+        return Err(anyhow::anyhow!(
+            "The memory was moved to collection {} but the alias {} could not be created: {:#}",
+            new_collection,
+            alias,
+            err
+        ));
+    }
+    Ok(true)
+}
+
+// Points the missing alias to the newest versioned collection, left behind when the bot stopped
+// between deleting the old collection and creating the alias. Returns its name, None if there
+// is none, then a new collection has to be created.
+pub fn recover_alias() -> anyhow::Result<Option<String>> {
+    let alias = env::var("QDRANT_COLLECTION_NAME")?;
+    let newest = newest_version(&alias, &qdrant::list_collections()?);
+    if let Some(collection) = &newest {
+        qdrant::switch_alias(&alias, collection)?;
+    }
+    Ok(newest)
+}
+
+// The collection named like "documents_1745000000" with the highest timestamp
+fn newest_version(alias: &str, collections: &[String]) -> Option<String> {
+    let prefix = format!("{}_", alias);
+    collections
+        .iter()
+        .filter_map(|name| {
+            let timestamp = name.strip_prefix(&prefix)?.parse::<u64>().ok()?;
+            Some((timestamp, name))
+        })
+        .max()
+        .map(|(_, name)| name.clone())
+}
+
+// The bot always talks to QDRANT_COLLECTION_NAME, an alias pointing to a versioned collection
+// like "documents_1745000000". A migration fills a new one and switches the alias in one request.
+pub fn migrate() -> anyhow::Result<String> {
+    let alias = env::var("QDRANT_COLLECTION_NAME")?;
+    let model = env::var("EMBEDDINGS_MODEL")?;
+    let current = qdrant::alias_target(&alias)?
+        .ok_or_else(|| anyhow::anyhow!("{} is not an alias, restart the bot first", alias))?;
+    let config = qdrant::collection_config(&current)?;
+    let size = ai::emb("dimension probe")?.len();
+    let docs = qdrant::all_documents()?;
+    let stale = docs
+        .iter()
+        .filter(|doc| doc.embedding_model.as_deref() != Some(model.as_str()))
+        .count();
+    if config.size == size && config.distance == "Cosine" && stale == 0 {
+        return Ok(format!(
+            "Memory already uses {} ({} dimensions), nothing to migrate.",
+            model, size
+        ));
+    }
+
+    let new_collection = qdrant::versioned_name(&alias)?;
+    qdrant::create_collection_with_size(&new_collection, size)?;
+    for doc in &docs {
+        if let Err(err) = qdrant::add_document_to(&new_collection, doc.id, &doc.text, &doc.metadata)
+        {
+            // The old collection is untouched, just drop the half-filled copy
+            let _ = qdrant::delete_collection(&new_collection);
+            return Err(err);
+        }
+    }
+
+    qdrant::switch_alias(&alias, &new_collection)?;
+    qdrant::delete_collection(&current)?;
+
+    let mut report = format!(
+        "Migrated {} memories to {} ({} -> {} dimensions).",
+        docs.len(),
+        model,
+        config.size,
+        size
+    );
+    if env::var("EMBEDDINGS_LENGTH").ok() != Some(size.to_string()) {
+        report.push_str(&format!("\nSet EMBEDDINGS_LENGTH={} in .env.", size));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newest_version_picks_the_latest_copy_of_the_alias() {
+        let collections = [
+            "documents_1745000000",
+            "documents_1746000000",
+            "documents_backup",
+            "other_1747000000",
+            "documents",
+        ]
+        .map(String::from);
+        assert_eq!(
+            newest_version("documents", &collections).as_deref(),
+            Some("documents_1746000000")
+        );
+        assert_eq!(newest_version("notes", &collections), None);
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use teloxide::prelude::*;
+use tokio::sync::oneshot;
+
+use crate::auth::{self, Capability};
+use crate::command::{self, Outcome};
+use crate::error::BotError;
+use crate::{ai, hosts, render, risk};
+
+struct Step {
+    command: String,
+    outcome: Outcome,
+}
+
+struct Plan {
+    goal: String,
+    // Registered host all steps run on, locally if None
+    host: Option<String>,
+    // Steps that have not run yet, the next one first. Revised after every step.
+    steps: Vec<String>,
+    done: Vec<Step>,
+    // Job ID and command of the step that is running
+    running: Option<(u64, String)>,
+    // Run the remaining steps without asking
+    all: bool,
+    // Let the LLM revise the steps after each one, fixed sequences (macros) are run as they are
+    revise: bool,
+}
+
+// Active plans, one per user and chat like the sessions
+static PLANS: LazyLock<Mutex<HashMap<(ChatId, u64), Plan>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Stores a new plan of the user, replacing an unfinished one, and returns the dry run:
+// every step with its risk and an explanation of the first one. Nothing runs until confirmed.
+pub fn propose(
+    chat_id: ChatId,
+    user_id: u64,
+    goal: &str,
+    host: Option<String>,
+    steps: Vec<String>,
+    revise: bool,
+) -> String {
+    let question = question(host.as_deref(), &[], &steps);
+    let plan = Plan {
+        goal: goal.to_string(),
+        host,
+        steps,
+        done: Vec::new(),
+        running: None,
+        all: false,
+        revise,
+    };
+    lock().insert((chat_id, user_id), plan);
+    question
+}
+
+pub fn is_active(chat_id: ChatId, user_id: u64) -> bool {
+    lock().contains_key(&(chat_id, user_id))
+}
+
+// Drops the plan, a running step goes on until it exits or is killed
+pub fn cancel(chat_id: ChatId, user_id: u64) -> bool {
+    lock().remove(&(chat_id, user_id)).is_some()
+}
+
+// Starts the next step, with `all` the following ones start by themselves after it succeeds
+pub fn confirm(
+    bot: Bot,
+    chat_id: ChatId,
+    user_id: u64,
+    user_name: &str,
+    all: bool,
+) -> anyhow::Result<String> {
+    let (command, host) = {
+        let mut plans = lock();
+        let plan = plans
+            .get_mut(&(chat_id, user_id))
+            .ok_or_else(|| BotError::user("There is no plan to run."))?;
+        if let Some((id, _)) = &plan.running {
+            plan.all |= all;
+            return Ok(if plan.all {
+                format!(
+                    "The remaining steps will run after job {} succeeds, /kill {} stops the plan.",
+                    id, id
+                )
+            } else {
+                format!(
+                    "Step {} is still running as job {}.",
+                    plan.done.len() + 1,
+                    id
+                )
+            });
+        }
+        if plan.steps.is_empty() {
+            return Err(BotError::user("There are no steps left to run."));
+        }
+        plan.all = all;
+        (plan.steps.remove(0), plan.host.clone())
+    };
+    // The host could have been removed while waiting for the confirmation
+    let target = match &host {
+        Some(name) => match hosts::find(name)? {
+            Some(host) => Some(host),
+            None => {
+                cancel(chat_id, user_id);
+                return Err(BotError::user(format!(
+                    "Host {} is not registered anymore.",
+                    name
+                )));
+            }
+        },
+        None => None,
+    };
+    let (id, outcome) =
+        command::start_watched(bot.clone(), chat_id, user_id, user_name, target, &command);
+    let step = {
+        let mut plans = lock();
+        let Some(plan) = plans.get_mut(&(chat_id, user_id)) else {
+            return Ok(format!("Job {} started, /kill {} stops it.", id, id));
+        };
+        plan.running = Some((id, command));
+        plan.done.len() + 1
+    };
+    let user_name = user_name.to_string();
+    tokio::spawn(advance(bot, chat_id, user_id, user_name, outcome));
+    Ok(format!(
+        "Step {} started as job {}, /kill {} stops it.",
+        step, id, id
+    ))
+}
+
+// Waits for the running step, stops the plan if it failed, otherwise lets the LLM revise the
+// remaining steps based on its output and asks about the next one or starts it
+async fn advance(
+    bot: Bot,
+    chat_id: ChatId,
+    user_id: u64,
+    user_name: String,
+    outcome: oneshot::Receiver<Outcome>,
+) {
+    let outcome = outcome.await.ok();
+    let text = tokio::task::spawn_blocking({
+        let bot = bot.clone();
+        move || next_step(bot, chat_id, user_id, &user_name, outcome)
+    })
+    .await
+    .unwrap_or_else(|err| Some(format!("Plan stopped: {}", err)));
+    if let Some(text) = text {
+        if let Err(err) = render::send(&bot, chat_id, &text).await {
+            tracing::error!(error = %err, "Plan message not delivered");
+        }
+    }
+}
+
+fn next_step(
+    bot: Bot,
+    chat_id: ChatId,
+    user_id: u64,
+    user_name: &str,
+    outcome: Option<Outcome>,
+) -> Option<String> {
+    let (goal, done, steps, revise) = {
+        let mut plans = lock();
+        // Cancelled meanwhile
+        let plan = plans.get_mut(&(chat_id, user_id))?;
+        let (id, command) = plan.running.take()?;
+        let step = plan.done.len() + 1;
+        let outcome = match outcome {
+            Some(outcome) if outcome.success() => outcome,
+            _ => {
+                plans.remove(&(chat_id, user_id));
+                return Some(format!(
+                    "Plan stopped: step {} (job {}) failed, the remaining steps were not run.",
+                    step, id
+                ));
+            }
+        };
+        plan.done.push(Step { command, outcome });
+        if plan.steps.is_empty() {
+            plans.remove(&(chat_id, user_id));
+            return Some(format!("Plan finished, {} steps done.", step));
+        }
+        (
+            plan.goal.clone(),
+            format_done(&plan.done),
+            plan.steps.clone(),
+            plan.revise,
+        )
+    };
+    // A failed revision keeps the steps planned before
+    let planned = steps.clone();
+    let steps = if revise {
+        revise_steps(&goal, &done, &steps).unwrap_or_else(|err| {
+            tracing::error!(error = format!("{:#}", err), "Plan revision failed");
+            steps
+        })
+    } else {
+        steps
+    };
+    let mut plans = lock();
+    let plan = plans.get_mut(&(chat_id, user_id))?;
+    if steps.is_empty() {
+        let count = plan.done.len();
+        plans.remove(&(chat_id, user_id));
+        return Some(format!(
+            "Plan finished after {} steps, nothing else is needed.",
+            count
+        ));
+    }
+    // "all" only covers the steps the user has seen, the revision is written from command
+    // output and could bring in anything
+    let changed = !only_seen(&steps, &planned);
+    if changed {
+        plan.all = false;
+    }
+    plan.steps = steps;
+    // "all" could have been sent meanwhile
+    if !plan.all {
+        let host = plan.host.clone();
+        let done = plan
+            .done
+            .iter()
+            .map(|step| step.command.clone())
+            .collect::<Vec<String>>();
+        let steps = plan.steps.clone();
+        // The explanation needs the LLM, the plans are not locked meanwhile
+        drop(plans);
+        let question = question(host.as_deref(), &done, &steps);
+        return Some(if changed {
+            format!(
+                "The remaining steps changed, please confirm again.\n{}",
+                question
+            )
+        } else {
+            question
+        });
+    }
+    drop(plans);
+    // Steps that start by themselves run with the rights the user has now
+    let allowed = auth::role_of(user_id)
+        .is_ok_and(|role| role.is_some_and(|role| role.can(Capability::RunCommands)));
+    if !allowed {
+        cancel(chat_id, user_id);
+        return Some("Plan stopped: you can no longer run commands.".to_string());
+    }
+    match confirm(bot, chat_id, user_id, user_name, true) {
+        Ok(_) => None,
+        Err(err) => {
+            cancel(chat_id, user_id);
+            Some(format!("Plan stopped: {}", err))
+        }
+    }
+}
+
+// True if the revised steps are the planned ones in the same order, some possibly dropped
+fn only_seen(revised: &[String], planned: &[String]) -> bool {
+    let mut planned = planned.iter();
+    revised.iter().all(|step| planned.any(|seen| seen == step))
+}
+
+fn revise_steps(goal: &str, done: &str, steps: &[String]) -> anyhow::Result<Vec<String>> {
+    let planned = steps
+        .iter()
+        .map(|step| format!("<command>{}</command>", step))
+        .collect::<String>();
+    let user = format!(
+        "<goal>{}</goal> These Linux commands were run to reach the goal, with the end of \
+         their output: {} The next commands planned were: {} Based on the output, respond with \
+         the commands still needed to reach the goal, in order, each in its own \
+         <command>COMMAND</command>. Respond with <done/> if nothing else is needed.",
+        goal, done, planned
+    );
+    let response = ai::llm("Give a short answer without explanations or details", &user)?;
+    Ok(ai::extract_tags(&response, "command"))
+}
+
+fn format_done(done: &[Step]) -> String {
+    done.iter()
+        .map(|step| {
+            format!(
+                "<step><command>{}</command><output>{}</output></step>",
+                step.command, step.outcome.output
+            )
+        })
+        .collect()
+}
+
+// Done steps, the ones left with their risk and an explanation of the next one
+fn question(host: Option<&str>, done: &[String], steps: &[String]) -> String {
+    let mut lines = vec![match host {
+        Some(host) => format!("Plan on {}:", host),
+        None => "Plan:".to_string(),
+    }];
+    for (i, command) in done.iter().enumerate() {
+        lines.push(format!("{}. ✅ `{}`", i + 1, command));
+    }
+    let first = done.len() + 1;
+    for (i, command) in steps.iter().enumerate() {
+        lines.push(format!(
+            "{}. `{}` — {}",
+            first + i,
+            command,
+            risk::static_risk(command)
+        ));
+    }
+    if let Some(next) = steps.first() {
+        let (explanation, risk) = risk::assess(next).unwrap_or_else(|err| {
+            tracing::error!(error = format!("{:#}", err), "Command analysis failed");
+            (String::new(), risk::static_risk(next))
+        });
+        lines.push(String::new());
+        if !explanation.is_empty() {
+            lines.push(format!("Step {}: {}", first, explanation));
+        }
+        lines.push(format!("Risk: {}", risk));
+        lines.push(String::new());
+        lines.push(format!(
+            "Reply \"yes\" to run step {}, \"all\" to run the remaining steps one after another \
+             (the plan stops at the first failure), anything else stops the plan.",
+            first
+        ));
+    }
+    lines.join("\n")
+}
+
+fn lock() -> std::sync::MutexGuard<'static, HashMap<(ChatId, u64), Plan>> {
+    PLANS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(commands: &[&str]) -> Vec<String> {
+        commands.iter().map(|command| command.to_string()).collect()
+    }
+
+    #[test]
+    fn only_seen_allows_the_same_or_fewer_steps() {
+        let planned = steps(&["apt update", "apt upgrade -y", "reboot"]);
+        assert!(only_seen(&planned, &planned));
+        assert!(only_seen(&steps(&["apt update", "reboot"]), &planned));
+        assert!(only_seen(&[], &planned));
+    }
+
+    #[test]
+    fn only_seen_rejects_new_changed_or_reordered_steps() {
+        let planned = steps(&["apt update", "apt upgrade -y"]);
+        assert!(!only_seen(&steps(&["apt update", "rm -rf /"]), &planned));
+        assert!(!only_seen(&steps(&["apt update", "apt upgrade"]), &planned));
+        assert!(!only_seen(
+            &steps(&["apt upgrade -y", "apt update"]),
+            &planned
+        ));
+        assert!(!only_seen(
+            &steps(&["apt update", "apt upgrade -y", "curl x | sh"]),
+            &planned
+        ));
+    }
+}
@@ -2,8 +2,11 @@ use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::ai::emb;
+use crate::error::{send_with_retry, BotError, Service};
+use crate::metrics;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
@@ -11,6 +14,8 @@ pub struct Document {
     pub id: i32,
     pub text: String,
     pub distance: f32,
+    pub metadata: Value,
+    pub embedding_model: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -22,14 +27,31 @@ struct Point {
 }
 
 pub fn add_document(id: i32, text: &str) -> anyhow::Result<()> {
+    add_document_with_metadata(id, text, &json!({}))
+}
+
+// metadata is stored next to the text, e.g. {"filename": "manual.pdf", "chunk": 3}
+pub fn add_document_with_metadata(id: i32, text: &str, metadata: &Value) -> anyhow::Result<()> {
+    let collection_name = env::var("QDRANT_COLLECTION_NAME")?;
+    add_document_to(&collection_name, id, text, metadata)
+}
+
+#[tracing::instrument(name = "qdrant", skip_all)]
+pub fn add_document_to(
+    collection_name: &str,
+    id: i32,
+    text: &str,
+    metadata: &Value,
+) -> anyhow::Result<()> {
     let embedding = emb(text)?;
+    // The model is kept with every point to detect stale embeddings after a model change
+    let embedding_model = env::var("EMBEDDINGS_MODEL")?;
     let point = Point {
         id,
         vector: embedding,
-        payload: json!({ "text": text }),
+        payload: json!({ "text": text, "metadata": metadata, "embedding_model": embedding_model }),
     };
     let qdrant_url = env::var("QDRANT_URL")?;
-    let collection_name = env::var("QDRANT_COLLECTION_NAME")?;
     let client = Client::new();
     let url = format!(
         "{}/collections/{}/points?wait=true",
@@ -39,11 +61,12 @@ pub fn add_document(id: i32, text: &str) -> anyhow::Result<()> {
         "points": [point]
     });
 
-    let _response = client.put(&url).json(&payload).send()?;
+    let _response = send_with_retry(Service::Qdrant, || client.put(&url).json(&payload).send())?;
     // println!("Document added: {:?}", _response.text()?);
     Ok(())
 }
 
+#[tracing::instrument(name = "qdrant", skip_all)]
 pub fn delete_document(id: i32) -> anyhow::Result<()> {
     let client = Client::new();
     let qdrant_url = env::var("QDRANT_URL")?;
@@ -56,50 +79,178 @@ pub fn delete_document(id: i32) -> anyhow::Result<()> {
         "points": [id]
     });
 
-    let _response = client
-        .post(&url)
-        .json(&payload)
-        .send()?
-        .error_for_status()?;
+    let _response = send_with_retry(Service::Qdrant, || client.post(&url).json(&payload).send())?;
 
     // println!("Document deleted: {:?}", _response.text()?);
     Ok(())
 }
 
+// QDRANT_COLLECTION_NAME is always an alias of a versioned collection, so a migration only
+// has to switch the alias
 pub fn create_collection() -> anyhow::Result<()> {
+    let alias = env::var("QDRANT_COLLECTION_NAME")?;
+    let embeddings_lenghth: usize = env::var("EMBEDDINGS_LENGTH")?.parse()?;
+    let collection_name = versioned_name(&alias)?;
+    create_collection_with_size(&collection_name, embeddings_lenghth)?;
+    switch_alias(&alias, &collection_name)
+}
+
+// A new collection name for the alias, like "documents_1745000000"
+pub fn versioned_name(alias: &str) -> anyhow::Result<String> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(format!("{}_{}", alias, timestamp))
+}
+
+#[tracing::instrument(name = "qdrant", skip_all)]
+pub fn create_collection_with_size(collection_name: &str, size: usize) -> anyhow::Result<()> {
     let qdrant_url = env::var("QDRANT_URL")?;
-    let collection_name = env::var("QDRANT_COLLECTION_NAME")?;
-    let embeddings_lenghth: i32 = env::var("EMBEDDINGS_LENGTH")?.parse()?;
     let client = Client::new();
-    let _response = client
-        .put(format!("{}/collections/{}", qdrant_url, collection_name))
-        .json(&json!({
-            "vectors": {
-                "size": embeddings_lenghth,
-                "distance": "Cosine"
-            }
-        }))
-        .send()?
-        .error_for_status()?;
+    let url = format!("{}/collections/{}", qdrant_url, collection_name);
+    let payload = json!({
+        "vectors": {
+            "size": size,
+            "distance": "Cosine"
+        }
+    });
+    let _response = send_with_retry(Service::Qdrant, || client.put(&url).json(&payload).send())?;
     // println!("Collection created: {:?}", _response.text()?);
     Ok(())
 }
 
-// Useful function when you change embedding and it has a different dimension,
-// and qdrant already has data with a different dimension
-#[allow(dead_code)]
-pub fn delete_collection() -> anyhow::Result<()> {
+#[tracing::instrument(name = "qdrant", skip_all)]
+pub fn delete_collection(collection_name: &str) -> anyhow::Result<()> {
     let qdrant_url = env::var("QDRANT_URL")?;
-    let collection_name = env::var("QDRANT_COLLECTION_NAME")?;
     let client = Client::new();
-    let _response = client
-        .delete(format!("{}/collections/{}", qdrant_url, collection_name))
-        .send()?
-        .error_for_status()?;
-    println!("Collection deleted: {:?}", _response.text()?);
+    let url = format!("{}/collections/{}", qdrant_url, collection_name);
+    let _response = send_with_retry(Service::Qdrant, || client.delete(&url).send())?;
+    tracing::info!(collection = collection_name, "Collection deleted");
     Ok(())
 }
 
+#[derive(Debug, Clone)]
+pub struct CollectionConfig {
+    pub size: usize,
+    pub distance: String,
+}
+
+#[tracing::instrument(name = "qdrant", skip_all)]
+pub fn collection_config(collection_name: &str) -> anyhow::Result<CollectionConfig> {
+    let qdrant_url = env::var("QDRANT_URL")?;
+    let client = Client::new();
+    let url = format!("{}/collections/{}", qdrant_url, collection_name);
+    let response: Value = send_with_retry(Service::Qdrant, || client.get(&url).send())?.json()?;
+    let vectors = &response["result"]["config"]["params"]["vectors"];
+    let size = vectors["size"].as_u64().ok_or(BotError::permanent(
+        Service::Qdrant,
+        "No vector size in collection config",
+    ))?;
+    Ok(CollectionConfig {
+        size: size as usize,
+        distance: vectors["distance"].as_str().unwrap_or_default().to_string(),
+    })
+}
+
+// Returns the collection the alias points to, None if there is no such alias
+pub fn alias_target(alias: &str) -> anyhow::Result<Option<String>> {
+    let qdrant_url = env::var("QDRANT_URL")?;
+    let client = Client::new();
+    let url = format!("{}/aliases", qdrant_url);
+    let response: Value = send_with_retry(Service::Qdrant, || client.get(&url).send())?.json()?;
+    let empty_vec = vec![];
+    let aliases = response["result"]["aliases"]
+        .as_array()
+        .unwrap_or(&empty_vec);
+    Ok(aliases
+        .iter()
+        .find(|item| item["alias_name"].as_str() == Some(alias))
+        .and_then(|item| item["collection_name"].as_str())
+        .map(|name| name.to_string()))
+}
+
+// Points the alias to the collection in one request, so searches never see a missing alias
+#[tracing::instrument(name = "qdrant", skip_all)]
+pub fn switch_alias(alias: &str, collection_name: &str) -> anyhow::Result<()> {
+    let qdrant_url = env::var("QDRANT_URL")?;
+    let mut actions = Vec::new();
+    if alias_target(alias)?.is_some() {
+        actions.push(json!({ "delete_alias": { "alias_name": alias } }));
+    }
+    actions.push(json!({
+        "create_alias": { "collection_name": collection_name, "alias_name": alias }
+    }));
+    let client = Client::new();
+    let url = format!("{}/collections/aliases", qdrant_url);
+    let payload = json!({ "actions": actions });
+    let _response = send_with_retry(Service::Qdrant, || client.post(&url).json(&payload).send())?;
+    Ok(())
+}
+
+// Copies the points with their vectors, nothing is embedded again
+#[tracing::instrument(name = "qdrant", skip_all)]
+pub fn copy_points(from: &str, to: &str) -> anyhow::Result<usize> {
+    let qdrant_url = env::var("QDRANT_URL")?;
+    let client = Client::new();
+    let scroll_url = format!("{}/collections/{}/points/scroll", qdrant_url, from);
+    let upsert_url = format!("{}/collections/{}/points?wait=true", qdrant_url, to);
+    let mut copied = 0;
+    let mut offset: Option<Value> = None;
+    loop {
+        let mut payload = json!({
+            "limit": 100,
+            "with_payload": true,
+            "with_vector": true,
+        });
+        if let Some(off) = &offset {
+            payload["offset"] = off.clone();
+        }
+        let response: Value = send_with_retry(Service::Qdrant, || {
+            client.post(&scroll_url).json(&payload).send()
+        })?
+        .json()?;
+        let result = &response["result"];
+        let points: Vec<Value> = result["points"]
+            .as_array()
+            .map(|points| {
+                points
+                    .iter()
+                    .map(|point| {
+                        json!({ "id": point["id"], "vector": point["vector"], "payload": point["payload"] })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        if points.is_empty() {
+            break;
+        }
+        copied += points.len();
+        let batch = json!({ "points": points });
+        send_with_retry(Service::Qdrant, || {
+            client.put(&upsert_url).json(&batch).send()
+        })?;
+        offset = Some(result["next_page_offset"].clone()).filter(|next| !next.is_null());
+        if offset.is_none() {
+            break;
+        }
+    }
+    Ok(copied)
+}
+
+// Names of all collections, not aliases
+pub fn list_collections() -> anyhow::Result<Vec<String>> {
+    let qdrant_url = env::var("QDRANT_URL")?;
+    let client = Client::new();
+    let url = format!("{}/collections", qdrant_url);
+    let response: Value = send_with_retry(Service::Qdrant, || client.get(&url).send())?.json()?;
+    let empty_vec = vec![];
+    Ok(response["result"]["collections"]
+        .as_array()
+        .unwrap_or(&empty_vec)
+        .iter()
+        .filter_map(|item| item["name"].as_str())
+        .map(|name| name.to_string())
+        .collect())
+}
+
 pub fn exists_collection() -> anyhow::Result<bool> {
     let qdrant_url = env::var("QDRANT_URL")?;
     let collection_name = env::var("QDRANT_COLLECTION_NAME")?;
@@ -107,7 +258,7 @@ pub fn exists_collection() -> anyhow::Result<bool> {
     let response = client
         .get(format!("{}/collections/{}", qdrant_url, collection_name))
         .send()?;
-    Ok(response.status().is_success())
+    Ok(response.status().is_success() || alias_target(&collection_name)?.is_some())
 }
 
 #[derive(Deserialize)]
@@ -132,6 +283,7 @@ pub fn last_document_id() -> anyhow::Result<i32> {
     }
     Ok(last_id)
 }
+#[tracing::instrument(name = "qdrant", skip_all)]
 pub fn all_documents() -> anyhow::Result<Vec<Document>> {
     let qdrant_url = env::var("QDRANT_URL")?;
     let collection_name = env::var("QDRANT_COLLECTION_NAME")?;
@@ -153,11 +305,8 @@ pub fn all_documents() -> anyhow::Result<Vec<Document>> {
             payload["offset"] = json!(off);
         }
 
-        let response = client
-            .post(&url)
-            .json(&payload)
-            .send()?
-            .error_for_status()?;
+        let response =
+            send_with_retry(Service::Qdrant, || client.post(&url).json(&payload).send())?;
         let scroll_response: serde_json::Value = response.json()?;
 
         if let Some(result) = scroll_response.get("result") {
@@ -180,6 +329,8 @@ pub fn all_documents() -> anyhow::Result<Vec<Document>> {
                         id: id as i32,
                         text,
                         distance: 0.0,
+                        metadata: payload_metadata(payload),
+                        embedding_model: payload_embedding_model(payload),
                     });
                 }
             }
@@ -202,7 +353,7 @@ pub fn all_documents() -> anyhow::Result<Vec<Document>> {
 pub fn search_one(query: &str) -> anyhow::Result<Document> {
     let documents = search(query, 1)?;
     if documents.is_empty() {
-        Err(anyhow::anyhow!("No documents found"))
+        Err(BotError::user("Nothing found in memory."))
     } else {
         Ok(documents[0].clone())
     }
@@ -228,6 +379,7 @@ pub fn search_smart(query: &str) -> anyhow::Result<Vec<Document>> {
     }
 }
 
+#[tracing::instrument(name = "qdrant", skip_all)]
 pub fn search(query: &str, limit: usize) -> anyhow::Result<Vec<Document>> {
     let query_vector = emb(query)?;
     let client = Client::new();
@@ -244,16 +396,17 @@ pub fn search(query: &str, limit: usize) -> anyhow::Result<Vec<Document>> {
         "with_vector": false,
     });
 
-    let response = client.post(&url).json(&payload).send()?;
-    let status = response.status();
-    if !status.is_success() {
-        return Err(anyhow::anyhow!(
-            "Search failed: {}",
-            response.text().unwrap_or(status.to_string())
-        ));
-    }
+    let response = send_with_retry(Service::Qdrant, || client.post(&url).json(&payload).send())?;
 
     let search_response: QdrantSearchResponse = response.json()?;
+    if let Some(item) = search_response.result.first() {
+        metrics::SEARCH_TOP_SCORE.observe(item.score as f64);
+    }
+    tracing::debug!(
+        results = search_response.result.len(),
+        top_score = search_response.result.first().map(|item| item.score),
+        "Search done"
+    );
 
     let documents = search_response
         .result
@@ -269,8 +422,24 @@ pub fn search(query: &str, limit: usize) -> anyhow::Result<Vec<Document>> {
                 id: item.id,
                 text,
                 distance: item.score,
+                metadata: payload_metadata(&item.payload),
+                embedding_model: payload_embedding_model(&item.payload),
             }
         })
         .collect();
     Ok(documents)
 }
+
+fn payload_metadata(payload: &Value) -> Value {
+    payload
+        .get("metadata")
+        .cloned()
+        .unwrap_or_else(|| json!({}))
+}
+
+fn payload_embedding_model(payload: &Value) -> Option<String> {
+    payload
+        .get("embedding_model")
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string())
+}
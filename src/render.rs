@@ -0,0 +1,301 @@
+use regex::Regex;
+use std::sync::LazyLock;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardMarkup, ParseMode};
+use teloxide::{ApiError, RequestError};
+
+// Telegram's limit for one message, after entities are parsed
+pub const MESSAGE_CHARS: usize = 4096;
+// Source text per message, leaves room for the HTML tags and escapes
+const CHUNK_CHARS: usize = 3500;
+
+static LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\[([^\]]+)\]\((https?://[^)\s"]+)\)"#).unwrap());
+static BOLD: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\*\*(\S(?:.*?\S)?)\*\*|__(\S(?:.*?\S)?)__").unwrap());
+static ITALIC: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(^|[^\w*])\*(\S(?:[^*]*?\S)?)\*($|[^\w*])|(^|[^\w])_(\S(?:[^_]*?\S)?)_($|[^\w])")
+        .unwrap()
+});
+static STRIKE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"~~(\S(?:.*?\S)?)~~").unwrap());
+static INLINE_CODE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"`([^`]+)`").unwrap());
+static HEADING: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^#{1,6}\s+(.*)$").unwrap());
+static BULLET: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(\s*)[*-]\s+(.*)$").unwrap());
+
+// Sends a Markdown reply (LLM answers, command output) as Telegram HTML, split into as many
+// messages as needed. A part Telegram can't parse is sent again as plain text.
+pub async fn send(bot: &Bot, chat_id: ChatId, text: &str) -> ResponseResult<()> {
+    send_with_keyboard(bot, chat_id, text, None).await
+}
+
+// Like `send`, the buttons go under the last message
+pub async fn send_with_keyboard(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> ResponseResult<()> {
+    let parts = render(text, CHUNK_CHARS);
+    let count = parts.len();
+    for (i, (source, html)) in parts.into_iter().enumerate() {
+        let keyboard = keyboard.clone().filter(|_| i + 1 == count);
+        let mut request = bot.send_message(chat_id, html).parse_mode(ParseMode::Html);
+        if let Some(keyboard) = keyboard.clone() {
+            request = request.reply_markup(keyboard);
+        }
+        match request.await {
+            Err(RequestError::Api(ApiError::CantParseEntities(err))) => {
+                tracing::warn!(error = err, "Sending as plain text");
+                let mut request = bot.send_message(chat_id, source);
+                if let Some(keyboard) = keyboard {
+                    request = request.reply_markup(keyboard);
+                }
+                request.await?;
+            }
+            result => {
+                result?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Escaping can make a part too long, then it is split again into smaller ones
+fn render(text: &str, limit: usize) -> Vec<(String, String)> {
+    let mut rendered = Vec::new();
+    for chunk in split(text, limit) {
+        let html = to_html(&chunk);
+        // 200 characters fit after any escaping, so this always ends
+        if html.chars().count() <= MESSAGE_CHARS || limit <= 200 {
+            rendered.push((chunk, html));
+        } else {
+            rendered.extend(render(&chunk, limit / 2));
+        }
+    }
+    rendered
+}
+
+// Splits at line breaks, or inside a line if it is longer than the limit. A code block cut in two
+// is closed at the end of one part and opened again in the next one.
+pub fn split(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_chars = 0;
+    // Opening line of the code block we are in
+    let mut fence: Option<String> = None;
+    for line in text.split('\n') {
+        let mut pieces = Vec::new();
+        let mut rest = line;
+        while rest.chars().count() > limit / 2 {
+            let cut = rest
+                .char_indices()
+                .nth(limit / 2)
+                .map_or(rest.len(), |(i, _)| i);
+            pieces.push(&rest[..cut]);
+            rest = &rest[cut..];
+        }
+        pieces.push(rest);
+        for piece in pieces {
+            let piece_chars = piece.chars().count() + 1;
+            // Room for the closing fence
+            if current_chars + piece_chars + 4 > limit && !current.is_empty() {
+                if fence.is_some() {
+                    current.push_str("```");
+                }
+                chunks.push(current.trim_end().to_string());
+                current = fence
+                    .as_ref()
+                    .map_or(String::new(), |fence| format!("{}\n", fence));
+                current_chars = current.chars().count();
+            }
+            current.push_str(piece);
+            current.push('\n');
+            current_chars += piece_chars;
+            if is_fence(piece) {
+                fence = match fence {
+                    Some(_) => None,
+                    None => Some(piece.trim().to_string()),
+                };
+            }
+        }
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current.trim_end().to_string());
+    }
+    chunks
+}
+
+// A line that opens or closes a code block, not a one-line ```code```
+fn is_fence(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("```") && !(line.len() > 6 && line.ends_with("```"))
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Escaped end of the text, at most `max` characters long after escaping
+pub fn escaped_tail(text: &str, max: usize) -> String {
+    let mut length = 0;
+    let mut start = text.len();
+    for (i, c) in text.char_indices().rev() {
+        length += match c {
+            '&' => 5,
+            '<' | '>' => 4,
+            _ => 1,
+        };
+        if length > max {
+            break;
+        }
+        start = i;
+    }
+    escape(&text[start..])
+}
+
+// Converts the Markdown that LLMs usually write into the HTML subset Telegram supports
+pub fn to_html(markdown: &str) -> String {
+    let mut html = String::new();
+    let mut in_code = false;
+    for line in markdown.split('\n') {
+        let trimmed = line.trim();
+        if is_fence(line) {
+            if in_code {
+                if html.ends_with('\n') {
+                    html.pop();
+                }
+                html.push_str("</code></pre>\n");
+            } else {
+                let language = trimmed.trim_start_matches('`').trim();
+                if language.is_empty() {
+                    html.push_str("<pre><code>");
+                } else {
+                    html.push_str(&format!(
+                        "<pre><code class=\"language-{}\">",
+                        escape(language).replace('"', "")
+                    ));
+                }
+            }
+            in_code = !in_code;
+        } else if in_code {
+            html.push_str(&escape(line));
+            html.push('\n');
+        } else if trimmed.len() > 6 && trimmed.starts_with("```") && trimmed.ends_with("```") {
+            html.push_str(&format!(
+                "<pre>{}</pre>\n",
+                escape(trimmed.trim_matches('`').trim())
+            ));
+        } else if let Some(caps) = HEADING.captures(line) {
+            html.push_str(&format!("<b>{}</b>\n", inline(&caps[1])));
+        } else if let Some(caps) = BULLET.captures(line) {
+            html.push_str(&format!("{}• {}\n", &caps[1], inline(&caps[2])));
+        } else {
+            html.push_str(&inline(line));
+            html.push('\n');
+        }
+    }
+    if in_code {
+        if html.ends_with('\n') {
+            html.pop();
+        }
+        html.push_str("</code></pre>");
+    }
+    html.trim_end().to_string()
+}
+
+// Inline code is left as is, the rest gets links, bold, italic and strikethrough
+fn inline(line: &str) -> String {
+    let mut html = String::new();
+    let mut last = 0;
+    for caps in INLINE_CODE.captures_iter(line) {
+        let code = caps.get(0).unwrap();
+        html.push_str(&format_text(&line[last..code.start()]));
+        html.push_str(&format!("<code>{}</code>", escape(&caps[1])));
+        last = code.end();
+    }
+    html.push_str(&format_text(&line[last..]));
+    html
+}
+
+fn format_text(text: &str) -> String {
+    let text = escape(text);
+    let text = LINK.replace_all(&text, r#"<a href="$2">$1</a>"#);
+    let text = BOLD.replace_all(&text, "<b>$1$2</b>");
+    let text = ITALIC.replace_all(&text, "$1$4<i>$2$5</i>$3$6");
+    let text = STRIKE.replace_all(&text, "<s>$1</s>");
+    text.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_keeps_short_text_whole() {
+        assert_eq!(split("one\ntwo", 100), vec!["one\ntwo"]);
+    }
+
+    #[test]
+    fn split_breaks_lines_at_the_limit() {
+        let text = ["123456789"; 10].join("\n");
+        let chunks = split(&text, 50);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 50));
+        assert_eq!(chunks.join("\n"), text);
+    }
+
+    #[test]
+    fn split_cuts_long_lines() {
+        let text = "x".repeat(100);
+        let chunks = split(&text, 20);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 20));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn split_closes_and_reopens_code_blocks() {
+        let text = format!("```rust\n{}\n```", ["let x = 1;"; 10].join("\n"));
+        let chunks = split(&text, 40);
+        assert!(chunks.len() > 1);
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert!(chunk.chars().count() <= 40);
+            assert!(chunk.starts_with("```rust\n"), "part {} not opened", i);
+            assert!(chunk.ends_with("```"), "part {} not closed", i);
+            assert_eq!(chunk.lines().filter(|line| is_fence(line)).count(), 2);
+        }
+    }
+
+    #[test]
+    fn to_html_escapes_and_formats() {
+        assert_eq!(to_html("a < b & c"), "a &lt; b &amp; c");
+        assert_eq!(
+            to_html("**bold**, *italic* and `x<y`"),
+            "<b>bold</b>, <i>italic</i> and <code>x&lt;y</code>"
+        );
+        assert_eq!(
+            to_html("[docs](https://example.com)"),
+            r#"<a href="https://example.com">docs</a>"#
+        );
+        assert_eq!(to_html("# Title\n- item"), "<b>Title</b>\n• item");
+    }
+
+    #[test]
+    fn to_html_converts_code_blocks() {
+        assert_eq!(
+            to_html("```rust\nif a < b {}\n```"),
+            "<pre><code class=\"language-rust\">if a &lt; b {}</code></pre>"
+        );
+        // Output cut in the middle of a block
+        assert_eq!(to_html("```\nls -l"), "<pre><code>ls -l</code></pre>");
+        assert_eq!(to_html("```ls```"), "<pre>ls</pre>");
+    }
+
+    #[test]
+    fn escaped_tail_never_cuts_an_entity() {
+        assert_eq!(escaped_tail("short", 10), "short");
+        assert_eq!(escaped_tail("abc<d", 5), "&lt;d");
+        assert_eq!(escaped_tail("a&b", 3), "b");
+    }
+}
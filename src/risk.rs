@@ -0,0 +1,312 @@
+use std::fmt;
+
+use crate::ai;
+
+// Ordered by severity, the highest one of all checks wins
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Risk {
+    ReadOnly,
+    ModifiesFiles,
+    Network,
+    Destructive,
+}
+
+impl Risk {
+    fn parse(name: &str) -> Option<Risk> {
+        match name.trim().to_lowercase().replace([' ', '_'], "-").as_str() {
+            "read-only" | "readonly" => Some(Risk::ReadOnly),
+            "modifies-files" => Some(Risk::ModifiesFiles),
+            "network" => Some(Risk::Network),
+            "destructive" => Some(Risk::Destructive),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Risk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Risk::ReadOnly => "🟢 read-only",
+            Risk::ModifiesFiles => "🟡 modifies files",
+            Risk::Network => "🟠 network access",
+            Risk::Destructive => "🔴 destructive",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+const DESTRUCTIVE: &[&str] = &[
+    "rm", "rmdir", "dd", "shred", "wipefs", "fdisk", "parted", "mkswap", "kill", "killall",
+    "pkill", "reboot", "shutdown", "halt", "poweroff", "truncate", "userdel", "groupdel",
+    "iptables", "crontab",
+];
+const NETWORK: &[&str] = &[
+    "curl", "wget", "ssh", "scp", "sftp", "rsync", "nc", "ncat", "telnet", "ftp", "ping", "dig",
+    "nslookup", "nmap", "apt", "apt-get", "yum", "dnf", "apk", "pip", "pip3", "npm", "cargo",
+];
+const MODIFIES: &[&str] = &[
+    "mv", "cp", "touch", "mkdir", "chmod", "chown", "chgrp", "ln", "tee", "install", "tar",
+    "unzip", "gzip", "gunzip", "patch", "useradd", "usermod", "passwd",
+];
+// Subcommands that make an otherwise harmless tool dangerous
+const DESTRUCTIVE_SUBCOMMANDS: &[(&str, &str)] = &[
+    ("systemctl", "stop"),
+    ("systemctl", "restart"),
+    ("systemctl", "disable"),
+    ("systemctl", "mask"),
+    ("docker", "rm"),
+    ("docker", "rmi"),
+    ("docker", "prune"),
+    ("docker", "kill"),
+    ("docker", "stop"),
+    ("git", "reset"),
+    ("git", "clean"),
+    ("git", "push"),
+    ("kubectl", "delete"),
+];
+
+// Programs that run the command after their own options, with the options that take a value
+// and the number of arguments before the command
+const WRAPPERS: &[(&str, &[&str], usize)] = &[
+    (
+        "sudo",
+        &["-u", "-g", "-C", "-D", "-h", "-p", "-r", "-t", "-U"],
+        0,
+    ),
+    ("doas", &["-u", "-C"], 0),
+    ("env", &["-u", "-C"], 0),
+    ("nohup", &[], 0),
+    ("timeout", &["-s", "-k", "--signal", "--kill-after"], 1),
+    ("nice", &["-n", "--adjustment"], 0),
+    ("ionice", &["-c", "-n", "-p"], 0),
+    ("stdbuf", &["-i", "-o", "-e"], 0),
+    ("time", &["-f", "-o"], 0),
+    ("command", &[], 0),
+    ("exec", &["-a"], 0),
+    ("watch", &["-n", "--interval"], 0),
+    (
+        "xargs",
+        &["-I", "-n", "-P", "-L", "-d", "-E", "-s", "-a"],
+        0,
+    ),
+];
+const SHELLS: &[&str] = &["sh", "bash", "zsh", "dash", "ksh"];
+
+// Static check of every part of a pipeline or command list. Unknown programs count as read-only,
+// the LLM analysis covers them.
+pub fn static_risk(command: &str) -> Risk {
+    let mut risk = Risk::ReadOnly;
+    let normalized = command
+        .replace("&&", ";")
+        .replace("||", ";")
+        .replace(['|', '\n', '&'], ";");
+    for part in normalized.split(';') {
+        let words: Vec<&str> = part.split_whitespace().collect();
+        risk = risk.max(words_risk(&words));
+    }
+    // Output redirection writes files, except for throwing the output away
+    let redirects = command
+        .replace("2>&1", "")
+        .replace(">/dev/null", "")
+        .replace("> /dev/null", "");
+    if redirects.contains('>') {
+        risk = risk.max(Risk::ModifiesFiles);
+    }
+    if redirects.contains("> /dev/sd") || redirects.contains(">/dev/sd") {
+        risk = Risk::Destructive;
+    }
+    risk
+}
+
+// Risk of one simple command, wrappers like sudo or xargs are rated by the command they run
+fn words_risk(words: &[&str]) -> Risk {
+    let words = unwrap_command(words);
+    let Some(program) = words.first() else {
+        return Risk::ReadOnly;
+    };
+    let program = program.trim_matches(['\'', '"']);
+    let program = program.rsplit('/').next().unwrap_or(program);
+    if SHELLS.contains(&program) {
+        // sh -c 'script', also combined flags like -ec
+        if let Some(i) = words
+            .iter()
+            .position(|word| word.starts_with('-') && !word.starts_with("--") && word.contains('c'))
+        {
+            let script = words[i + 1..].join(" ");
+            return static_risk(script.trim_matches(['\'', '"']));
+        }
+    }
+    if program == "find" {
+        if words.contains(&"-delete") {
+            return Risk::Destructive;
+        }
+        let exec = ["-exec", "-execdir", "-ok", "-okdir"];
+        if let Some(i) = words.iter().position(|word| exec.contains(word)) {
+            return words_risk(&words[i + 1..]);
+        }
+    }
+    if DESTRUCTIVE.contains(&program)
+        || program.starts_with("mkfs")
+        || DESTRUCTIVE_SUBCOMMANDS
+            .iter()
+            .any(|(tool, sub)| *tool == program && words.iter().skip(1).any(|w| w == sub))
+    {
+        Risk::Destructive
+    } else if NETWORK.contains(&program) {
+        Risk::Network
+    } else if MODIFIES.contains(&program)
+        || (program == "sed" && words.iter().any(|w| w.starts_with("-i")))
+    {
+        Risk::ModifiesFiles
+    } else {
+        Risk::ReadOnly
+    }
+}
+
+// Skips variable assignments and wrapper programs with their options
+fn unwrap_command<'a, 'b>(mut words: &'b [&'a str]) -> &'b [&'a str] {
+    loop {
+        while words
+            .first()
+            .is_some_and(|word| word.contains('=') && !word.starts_with('-'))
+        {
+            words = &words[1..];
+        }
+        let Some(program) = words.first() else {
+            return words;
+        };
+        let program = program.rsplit('/').next().unwrap_or(program);
+        let Some((_, with_value, arguments)) =
+            WRAPPERS.iter().find(|(wrapper, _, _)| *wrapper == program)
+        else {
+            return words;
+        };
+        let mut i = 1;
+        while let Some(word) = words.get(i) {
+            if *word == "--" {
+                i += 1;
+                break;
+            }
+            if !word.starts_with('-') {
+                break;
+            }
+            i += if with_value.contains(word) { 2 } else { 1 };
+        }
+        words = words.get(i + arguments..).unwrap_or_default();
+    }
+}
+
+// Plain-language explanation and the risk, the higher of the static and the LLM ratings
+pub fn assess(command: &str) -> anyhow::Result<(String, Risk)> {
+    let user = format!(
+        "<command>{}</command> Explain in one or two plain sentences what this Linux command does, \
+         for someone who doesn't know the shell. Rate its risk as one of: read-only, \
+         modifies-files, network, destructive. Respond in the format \
+         <explanation>EXPLANATION</explanation><risk>RISK</risk>",
+        command
+    );
+    let response = ai::llm("Give a short answer without greetings", &user)?;
+    let explanation = ai::extract_tag(&response, "explanation");
+    let llm_risk = Risk::parse(&ai::extract_tag(&response, "risk")).unwrap_or(Risk::ReadOnly);
+    Ok((explanation, static_risk(command).max(llm_risk)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn static_risk_of_read_only_commands() {
+        assert_eq!(static_risk("ls -la /tmp"), Risk::ReadOnly);
+        assert_eq!(static_risk("cat log | grep error"), Risk::ReadOnly);
+        assert_eq!(static_risk("systemctl status nginx"), Risk::ReadOnly);
+        assert_eq!(
+            static_risk("find / -name x 2>&1 >/dev/null"),
+            Risk::ReadOnly
+        );
+    }
+
+    #[test]
+    fn static_risk_of_file_changes() {
+        assert_eq!(static_risk("cp a b"), Risk::ModifiesFiles);
+        assert_eq!(static_risk("sed -i s/a/b/ config"), Risk::ModifiesFiles);
+        assert_eq!(static_risk("echo hi > notes.txt"), Risk::ModifiesFiles);
+    }
+
+    #[test]
+    fn static_risk_of_network_access() {
+        assert_eq!(static_risk("curl https://example.com"), Risk::Network);
+        assert_eq!(static_risk("sudo apt-get install vim"), Risk::Network);
+        // The highest risk of all parts wins
+        assert_eq!(
+            static_risk("curl https://example.com > page"),
+            Risk::Network
+        );
+    }
+
+    #[test]
+    fn static_risk_of_destructive_commands() {
+        assert_eq!(static_risk("rm -rf /tmp/build"), Risk::Destructive);
+        assert_eq!(static_risk("ls && sudo /bin/rm x"), Risk::Destructive);
+        assert_eq!(static_risk("FOO=1 env kill 1"), Risk::Destructive);
+        assert_eq!(static_risk("mkfs.ext4 /dev/sdb1"), Risk::Destructive);
+        assert_eq!(static_risk("systemctl restart nginx"), Risk::Destructive);
+        assert_eq!(static_risk("git push origin main"), Risk::Destructive);
+        assert_eq!(static_risk("cat image > /dev/sda"), Risk::Destructive);
+    }
+
+    #[test]
+    fn static_risk_looks_through_wrappers() {
+        assert_eq!(static_risk("sudo -u root rm x"), Risk::Destructive);
+        assert_eq!(static_risk("nohup rm -rf /tmp/x &"), Risk::Destructive);
+        assert_eq!(static_risk("timeout 10 rm x"), Risk::Destructive);
+        assert_eq!(
+            static_risk("timeout -s KILL 10s shred x"),
+            Risk::Destructive
+        );
+        assert_eq!(static_risk("nice -n 10 rm x"), Risk::Destructive);
+        assert_eq!(static_risk("sudo nice rm x"), Risk::Destructive);
+        assert_eq!(static_risk("timeout 10 ls"), Risk::ReadOnly);
+    }
+
+    #[test]
+    fn static_risk_of_commands_run_by_xargs_and_shells() {
+        assert_eq!(
+            static_risk("find . -name '*.log' | xargs rm"),
+            Risk::Destructive
+        );
+        assert_eq!(
+            static_risk("ls | xargs -n 1 -I {} rm {}"),
+            Risk::Destructive
+        );
+        assert_eq!(static_risk("ls | xargs echo"), Risk::ReadOnly);
+        assert_eq!(static_risk("sh -c 'rm -rf /tmp/x'"), Risk::Destructive);
+        assert_eq!(
+            static_risk("bash -ec \"curl https://example.com\""),
+            Risk::Network
+        );
+        assert_eq!(static_risk("bash -c 'echo hi'"), Risk::ReadOnly);
+    }
+
+    #[test]
+    fn static_risk_of_find_actions() {
+        assert_eq!(static_risk("find / -delete"), Risk::Destructive);
+        assert_eq!(
+            static_risk("find . -name x -exec rm {} \\;"),
+            Risk::Destructive
+        );
+        assert_eq!(
+            static_risk("find . -exec chmod 644 {} +"),
+            Risk::ModifiesFiles
+        );
+        assert_eq!(static_risk("find . -name x -print"), Risk::ReadOnly);
+    }
+
+    #[test]
+    fn parse_llm_ratings() {
+        assert_eq!(Risk::parse(" Read-only "), Some(Risk::ReadOnly));
+        assert_eq!(Risk::parse("modifies files"), Some(Risk::ModifiesFiles));
+        assert_eq!(Risk::parse("DESTRUCTIVE"), Some(Risk::Destructive));
+        assert_eq!(Risk::parse("maybe"), None);
+    }
+}
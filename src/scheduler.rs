@@ -0,0 +1,498 @@
+use chrono::{DateTime, Local, Months, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use teloxide::prelude::*;
+
+use crate::auth::Capability;
+use crate::error::BotError;
+use crate::hosts::{self, Host};
+use crate::{ai, auth, command, render, storage};
+
+const JOBS_FILE: &str = "jobs.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Repeat {
+    Once,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+    // Standard 5-field cron expression in local time, e.g. "0 8 * * MON-FRI"
+    Cron(String),
+}
+
+impl Repeat {
+    fn parse(name: &str) -> Repeat {
+        match name.trim().to_lowercase().as_str() {
+            "daily" => Repeat::Daily,
+            "weekly" => Repeat::Weekly,
+            "monthly" => Repeat::Monthly,
+            "yearly" => Repeat::Yearly,
+            _ => Repeat::Once,
+        }
+    }
+}
+
+impl fmt::Display for Repeat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Repeat::Once => "once",
+            Repeat::Daily => "every day",
+            Repeat::Weekly => "every week",
+            Repeat::Monthly => "every month",
+            Repeat::Yearly => "every year",
+            Repeat::Cron(cron) => return write!(f, "cron \"{}\"", cron),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Action {
+    Remind {
+        text: String,
+    },
+    // Shell command, its output is sent to the chat
+    Command {
+        command: String,
+        // Registered host to run it on over SSH
+        #[serde(default)]
+        host: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub chat_id: i64,
+    pub user_id: u64,
+    pub at: DateTime<Utc>,
+    // First occurrence, later ones are counted from it so a day cut short by a month comes back
+    #[serde(default)]
+    pub first: Option<DateTime<Utc>>,
+    pub repeat: Repeat,
+    pub action: Action,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Jobs {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+#[derive(Deserialize)]
+struct Extracted {
+    time: String,
+    #[serde(default)]
+    repeat: String,
+    text: String,
+}
+
+// Finds a date in the text and turns it into a reminder: (time, repeat, what to remind about).
+// Times are in the server's local time zone (TZ).
+pub fn extract(text: &str) -> anyhow::Result<Option<(DateTime<Utc>, Repeat, String)>> {
+    let now = Local::now();
+    let user = format!(
+        "Now is {}. <message>{}</message>\n\
+         If the message contains a date or time worth a reminder, answer with JSON \
+         {{\"time\": \"YYYY-MM-DD HH:MM\", \"repeat\": \"once|daily|weekly|monthly|yearly\", \
+         \"text\": \"what to remind about\"}}. Use the next upcoming occurrence, 09:00 when \
+         there is no time, and yearly for birthdays and anniversaries. Otherwise answer {{}}.",
+        now.format("%Y-%m-%d %H:%M, %A"),
+        text
+    );
+    let response = ai::llm("Answer with JSON only, without explanations", &user)?;
+    let json = match (response.find('{'), response.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &response[start..=end],
+        _ => return Ok(None),
+    };
+    let Ok(extracted) = serde_json::from_str::<Extracted>(json) else {
+        return Ok(None);
+    };
+    let Ok(time) = NaiveDateTime::parse_from_str(&extracted.time, "%Y-%m-%d %H:%M") else {
+        return Ok(None);
+    };
+    let Some(at) = local_to_utc(time) else {
+        return Ok(None);
+    };
+    let repeat = Repeat::parse(&extracted.repeat);
+    // Past dates of repeating events move to the next occurrence, past one-offs are dropped
+    let now = Utc::now();
+    let at = if at > now {
+        Some(at)
+    } else {
+        next_after(at, &repeat, now)
+    };
+    Ok(at.map(|at| (at, repeat, extracted.text)))
+}
+
+fn local_to_utc(time: NaiveDateTime) -> Option<DateTime<Utc>> {
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+const WEEKDAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+// The cron crate wants seconds as the first field
+pub fn parse_cron(cron: &str) -> anyhow::Result<Schedule> {
+    let fields: Vec<&str> = cron.split_whitespace().collect();
+    if fields.len() != 5 {
+        return Err(BotError::user(format!(
+            "\"{}\" is not a cron expression with 5 fields",
+            cron
+        )));
+    }
+    let invalid = |err: &dyn fmt::Display| {
+        BotError::user(format!("Invalid cron expression \"{}\": {}", cron, err))
+    };
+    let weekdays = weekdays(fields[4]).map_err(|err| invalid(&err))?;
+    Schedule::from_str(&format!("0 {} {}", fields[..4].join(" "), weekdays))
+        .map_err(|err| invalid(&err))
+}
+
+// The cron crate numbers weekdays 1-7 from Sunday and has no ranges across the week's end,
+// so standard weekdays (0-7 with Sunday as 0 or 7, or names like MON-SUN) become a list of names
+fn weekdays(field: &str) -> Result<String, String> {
+    if field == "*" || field == "?" {
+        return Ok(field.to_string());
+    }
+    let day = |value: &str| -> Result<usize, String> {
+        match value.parse::<usize>() {
+            Ok(number) if number <= 7 => Ok(number),
+            Ok(_) => Err(format!("day of week {} is not 0-7", value)),
+            Err(_) => WEEKDAYS
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(value))
+                // Sunday at the end of a range is the last day
+                .map(|day| if day == 0 { 7 } else { day })
+                .ok_or_else(|| format!("unknown day of week \"{}\"", value)),
+        }
+    };
+    let mut days = [false; 7];
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<usize>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("invalid step in \"{}\"", item))?,
+            ),
+            None => (item, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((first, last)) => (day(first)? % 7, day(last)?),
+            None if step > 1 => (day(range)? % 7, 6),
+            None => {
+                let day = day(range)?;
+                (day, day)
+            }
+        };
+        if first > last {
+            return Err(format!("invalid range \"{}\"", range));
+        }
+        for day in (first..=last).step_by(step) {
+            days[day % 7] = true;
+        }
+    }
+    Ok(WEEKDAYS
+        .iter()
+        .zip(days)
+        .filter(|(_, selected)| *selected)
+        .map(|(name, _)| *name)
+        .collect::<Vec<&str>>()
+        .join(","))
+}
+
+// The first occurrence after `now` of a job first due at `first`, None for one-off jobs
+pub fn next_after(
+    first: DateTime<Utc>,
+    repeat: &Repeat,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    if let Repeat::Cron(cron) = repeat {
+        let schedule = parse_cron(cron).ok()?;
+        return schedule
+            .after(&now.with_timezone(&Local))
+            .next()
+            .map(|next| next.with_timezone(&Utc));
+    }
+    let first = first.with_timezone(&Local).naive_local();
+    // Months are added to the first occurrence, not the previous one, so the 31st
+    // becomes the 28th in February and the 31st again in March
+    for n in 1u32.. {
+        let local = match repeat {
+            Repeat::Once => return None,
+            Repeat::Daily => first + TimeDelta::days(n.into()),
+            Repeat::Weekly => first + TimeDelta::weeks(n.into()),
+            Repeat::Monthly => first.checked_add_months(Months::new(n))?,
+            Repeat::Yearly => first.checked_add_months(Months::new(n.checked_mul(12)?))?,
+            Repeat::Cron(_) => unreachable!(),
+        };
+        // The time doesn't exist on a day clocks go forward, that occurrence is skipped
+        let Some(next) = local_to_utc(local) else {
+            continue;
+        };
+        if next > now {
+            return Some(next);
+        }
+    }
+    None
+}
+
+pub fn add(
+    chat_id: i64,
+    user_id: u64,
+    at: DateTime<Utc>,
+    repeat: Repeat,
+    action: Action,
+) -> anyhow::Result<Job> {
+    storage::update_json(JOBS_FILE, |jobs: &mut Jobs| {
+        jobs.next_id += 1;
+        let job = Job {
+            id: jobs.next_id,
+            chat_id,
+            user_id,
+            at,
+            first: Some(at),
+            repeat,
+            action,
+        };
+        jobs.jobs.push(job.clone());
+        job
+    })
+}
+
+pub fn list(chat_id: i64) -> anyhow::Result<Vec<Job>> {
+    let jobs: Jobs = storage::load_json(JOBS_FILE)?;
+    let mut jobs: Vec<Job> = jobs
+        .jobs
+        .into_iter()
+        .filter(|job| job.chat_id == chat_id)
+        .collect();
+    jobs.sort_by_key(|job| job.at);
+    Ok(jobs)
+}
+
+// Only jobs of the same chat can be cancelled, and only the owner's ones unless `owner` is None.
+// Returns false if there was no such job.
+pub fn cancel(chat_id: i64, id: u64, owner: Option<u64>) -> anyhow::Result<bool> {
+    storage::update_json(JOBS_FILE, |jobs: &mut Jobs| {
+        let count = jobs.jobs.len();
+        jobs.jobs.retain(|job| {
+            !(job.id == id
+                && job.chat_id == chat_id
+                && owner.is_none_or(|user| user == job.user_id))
+        });
+        jobs.jobs.len() != count
+    })
+}
+
+// Removes due one-off jobs and moves repeating ones to their next time. Jobs missed while
+// the bot was down are returned once.
+fn take_due() -> anyhow::Result<Vec<Job>> {
+    let now = Utc::now();
+    // Checked first so the file isn't rewritten on every tick
+    let jobs: Jobs = storage::load_json(JOBS_FILE)?;
+    if jobs.jobs.iter().all(|job| job.at > now) {
+        return Ok(Vec::new());
+    }
+    storage::update_json(JOBS_FILE, |jobs: &mut Jobs| {
+        let (due, mut pending): (Vec<Job>, Vec<Job>) = std::mem::take(&mut jobs.jobs)
+            .into_iter()
+            .partition(|job| job.at <= now);
+        for job in &due {
+            let first = job.first.unwrap_or(job.at);
+            if let Some(at) = next_after(first, &job.repeat, now) {
+                pending.push(Job {
+                    at,
+                    first: Some(first),
+                    ..job.clone()
+                });
+            }
+        }
+        jobs.jobs = pending;
+        due
+    })
+}
+
+pub fn format_job(job: &Job) -> String {
+    let what = match &job.action {
+        Action::Remind { text } => text.clone(),
+        Action::Command {
+            command,
+            host: Some(host),
+        } => format!("run \"{}\" on {}", command, host),
+        Action::Command { command, .. } => format!("run \"{}\"", command),
+    };
+    let at = job.at.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+    match &job.repeat {
+        Repeat::Once => format!("{}. {} — {}", job.id, at, what),
+        repeat => format!("{}. {} ({}) — {}", job.id, at, repeat, what),
+    }
+}
+
+// Scheduled commands run with the rights their owner has now, not when they were scheduled
+fn command_target(job: &Job, host: Option<&str>) -> anyhow::Result<Option<Host>> {
+    if !auth::role_of(job.user_id)?.is_some_and(|role| role.can(Capability::RunCommands)) {
+        return Err(BotError::user("its owner can no longer run commands."));
+    }
+    match host {
+        Some(name) => hosts::find(name)?
+            .map(Some)
+            .ok_or_else(|| BotError::user(format!("host {} is not registered anymore.", name))),
+        None => Ok(None),
+    }
+}
+
+// Checks the jobs every 30 seconds, runs the due ones and sends the results to their chats
+pub async fn run(bot: Bot) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        let due = match tokio::task::spawn_blocking(take_due).await {
+            Ok(Ok(due)) => due,
+            Ok(Err(err)) => {
+                tracing::error!(error = format!("{:#}", err), "Scheduler failed");
+                continue;
+            }
+            Err(err) => {
+                tracing::error!(error = %err, "Scheduler failed");
+                continue;
+            }
+        };
+        for job in due {
+            tracing::info!(job = job.id, chat_id = job.chat_id, "Running scheduled job");
+            let text = match job.action.clone() {
+                Action::Remind { text } => format!("⏰ {}", text),
+                Action::Command { command, host } => {
+                    let owner = job.clone();
+                    let target = tokio::task::spawn_blocking(move || {
+                        command_target(&owner, host.as_deref())
+                    })
+                    .await
+                    .unwrap_or_else(|err| Err(err.into()));
+                    match target {
+                        Ok(target) => {
+                            let chat_id = ChatId(job.chat_id);
+                            command::start(
+                                bot.clone(),
+                                chat_id,
+                                job.user_id,
+                                "scheduler",
+                                target,
+                                &command,
+                            );
+                            continue;
+                        }
+                        Err(err) => format!("Scheduled job {} skipped: {}", job.id, err),
+                    }
+                }
+            };
+            if let Err(err) = render::send(&bot, ChatId(job.chat_id), &text).await {
+                tracing::error!(job = job.id, error = %err, "Scheduled message not delivered");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(year: i32, month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+        Local
+            .with_ymd_and_hms(year, month, day, hour, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn weekdays_accept_numbers_and_names() {
+        assert_eq!(weekdays("1-5").unwrap(), "MON,TUE,WED,THU,FRI");
+        assert_eq!(weekdays("mon-fri").unwrap(), "MON,TUE,WED,THU,FRI");
+        assert_eq!(weekdays("0").unwrap(), "SUN");
+        assert_eq!(weekdays("7").unwrap(), "SUN");
+        assert_eq!(weekdays("FRI-SUN").unwrap(), "SUN,FRI,SAT");
+        assert_eq!(weekdays("1,3,SAT").unwrap(), "MON,WED,SAT");
+        assert_eq!(weekdays("*/2").unwrap(), "SUN,TUE,THU,SAT");
+        assert_eq!(weekdays("*").unwrap(), "*");
+    }
+
+    #[test]
+    fn weekdays_reject_invalid_days() {
+        assert!(weekdays("8").is_err());
+        assert!(weekdays("XYZ").is_err());
+        assert!(weekdays("5-1").is_err());
+        assert!(weekdays("1/0").is_err());
+    }
+
+    #[test]
+    fn parse_cron_checks_the_expression() {
+        assert!(parse_cron("0 8 * * MON-FRI").is_ok());
+        assert!(parse_cron("30 18 1 * *").is_ok());
+        assert!(parse_cron("0 8 * *").is_err());
+        assert!(parse_cron("61 8 * * *").is_err());
+        assert!(parse_cron("0 8 * * FOO").is_err());
+    }
+
+    #[test]
+    fn next_after_follows_named_weekdays() {
+        // Saturday noon
+        let now = local(2026, 10, 17, 12);
+        let workdays = Repeat::Cron("0 8 * * MON-FRI".to_string());
+        assert_eq!(
+            next_after(now, &workdays, now),
+            Some(local(2026, 10, 19, 8))
+        );
+        let sundays = Repeat::Cron("0 9 * * 0".to_string());
+        assert_eq!(next_after(now, &sundays, now), Some(local(2026, 10, 18, 9)));
+    }
+
+    #[test]
+    fn next_after_moves_past_the_missed_occurrences() {
+        let at = local(2026, 6, 1, 9);
+        let now = local(2026, 6, 3, 12);
+        assert_eq!(
+            next_after(at, &Repeat::Daily, now),
+            Some(local(2026, 6, 4, 9))
+        );
+        assert_eq!(
+            next_after(at, &Repeat::Weekly, now),
+            Some(local(2026, 6, 8, 9))
+        );
+        assert_eq!(
+            next_after(at, &Repeat::Monthly, now),
+            Some(local(2026, 7, 1, 9))
+        );
+        assert_eq!(next_after(at, &Repeat::Once, now), None);
+    }
+
+    #[test]
+    fn next_after_keeps_the_day_of_month() {
+        let first = local(2026, 1, 31, 9);
+        let february = next_after(first, &Repeat::Monthly, first).unwrap();
+        assert_eq!(february, local(2026, 2, 28, 9));
+        assert_eq!(
+            next_after(first, &Repeat::Monthly, february),
+            Some(local(2026, 3, 31, 9))
+        );
+        let leap_day = local(2028, 2, 29, 9);
+        assert_eq!(
+            next_after(leap_day, &Repeat::Yearly, local(2029, 3, 1, 0)),
+            Some(local(2030, 2, 28, 9))
+        );
+        assert_eq!(
+            next_after(leap_day, &Repeat::Yearly, local(2031, 3, 1, 0)),
+            Some(local(2032, 2, 29, 9))
+        );
+    }
+}
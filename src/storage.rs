@@ -0,0 +1,48 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// Serializes read-modify-write of all JSON files, the updates are small and rare
+static UPDATE_LOCK: Mutex<()> = Mutex::new(());
+
+// Bot's own state (users, schedules, ...) lives in DATA_DIR as JSON files
+pub fn data_path(file_name: &str) -> anyhow::Result<PathBuf> {
+    let dir = PathBuf::from(env::var("DATA_DIR").unwrap_or_else(|_| "data".to_string()));
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(file_name))
+}
+
+pub fn load_json<T: DeserializeOwned + Default>(file_name: &str) -> anyhow::Result<T> {
+    let path = data_path(file_name)?;
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let content = fs::read_to_string(&path)?;
+    serde_json::from_str(&content)
+        .map_err(|err| anyhow::anyhow!("Broken {}: {}", path.display(), err))
+}
+
+// Writes to a temporary file first, so a crash never leaves a half-written file
+pub fn save_json<T: Serialize>(file_name: &str, value: &T) -> anyhow::Result<()> {
+    let path = data_path(file_name)?;
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_string_pretty(value)?)?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+// Loads the file, lets `update` change the value and saves it, under one lock so concurrent
+// updates don't overwrite each other
+pub fn update_json<T, R>(file_name: &str, update: impl FnOnce(&mut T) -> R) -> anyhow::Result<R>
+where
+    T: Serialize + DeserializeOwned + Default,
+{
+    let _guard = UPDATE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let mut value: T = load_json(file_name)?;
+    let result = update(&mut value);
+    save_json(file_name, &value)?;
+    Ok(result)
+}
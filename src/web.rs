@@ -0,0 +1,314 @@
+use regex::Regex;
+use reqwest::blocking::Client;
+use reqwest::dns::{Name, Resolve, Resolving};
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::Url;
+use serde_json::json;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::ai;
+use crate::error::{send_with_retry, BotError, Service};
+use crate::ingest;
+use crate::qdrant;
+
+// Enough text for the summary prompt without overflowing small context windows
+const SUMMARY_INPUT_LEN: usize = 12000;
+// Larger pages are cut, their beginning is enough for the memory
+const MAX_PAGE_BYTES: u64 = 2 * 1024 * 1024;
+
+pub struct Page {
+    pub title: String,
+    pub text: String,
+}
+
+pub fn find_url(message: &str) -> Option<String> {
+    let re = Regex::new(r#"https?://[^\s<>"']+"#).unwrap();
+    re.find(message).map(|m| {
+        m.as_str()
+            .trim_end_matches(['.', ',', ')', ';', '!', '?'])
+            .to_string()
+    })
+}
+
+// Fetches the page, saves its summary and its text split into chunks.
+// Returns the page title and the summary.
+pub fn remember_url(url: &str) -> anyhow::Result<(String, String)> {
+    let page = fetch_page(url)?;
+    let chunks = ingest::split_into_chunks(&page.text, ingest::CHUNK_SIZE);
+    if chunks.is_empty() {
+        return Err(BotError::user(format!("No readable text found at {}", url)));
+    }
+    let excerpt: String = page.text.chars().take(SUMMARY_INPUT_LEN).collect();
+    let user = format!(
+        "<page title=\"{}\">{}</page> Summarize the page in a few sentences, \
+         keeping names, numbers and other key facts.",
+        page.title, excerpt
+    );
+    let summary = ai::llm(
+        "Give a short answer without greetings or explanations",
+        &user,
+    )?;
+
+    let id = qdrant::last_document_id()? + 1;
+    let metadata = json!({ "source_url": url, "title": page.title, "kind": "summary" });
+    qdrant::add_document_with_metadata(id, &format!("{}\n{}", page.title, summary), &metadata)?;
+    let metadata = json!({ "source_url": url, "title": page.title, "kind": "chunk" });
+    ingest::save_chunks(&chunks, &metadata)?;
+    Ok((page.title, summary))
+}
+
+// Only HTML pages on public addresses, so links can't read the bot's internal services
+// (Qdrant, the metrics endpoint, ...) into memory
+pub fn fetch_page(url: &str) -> anyhow::Result<Page> {
+    let parsed = Url::parse(url).map_err(|_| BotError::user(format!("Invalid link {}", url)))?;
+    if !is_public(&parsed) {
+        return Err(BotError::user(
+            "Only pages on public addresses can be saved.",
+        ));
+    }
+    let client = Client::builder()
+        .timeout(Duration::from_secs(30))
+        .user_agent("Mozilla/5.0 (compatible; ai-agent-telegram-bot)")
+        .redirect(Policy::custom(check_redirect))
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()?;
+    let response = send_with_retry(Service::Web, || client.get(url).send())?;
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_lowercase();
+    if !content_type.starts_with("text/html") && !content_type.starts_with("application/xhtml+xml")
+    {
+        return Err(BotError::user(format!(
+            "{} is not a web page ({}).",
+            url,
+            if content_type.is_empty() {
+                "no content type"
+            } else {
+                &content_type
+            }
+        )));
+    }
+    let mut bytes = Vec::new();
+    response.take(MAX_PAGE_BYTES).read_to_end(&mut bytes)?;
+    let html = String::from_utf8_lossy(&bytes);
+    Ok(Page {
+        title: extract_title(&html).unwrap_or_else(|| url.to_string()),
+        text: readable_text(&html)?,
+    })
+}
+
+// Redirects are checked like the link itself
+fn check_redirect(attempt: Attempt) -> reqwest::redirect::Action {
+    if attempt.previous().len() >= 10 {
+        attempt.error("too many redirects")
+    } else if is_public(attempt.url()) {
+        attempt.follow()
+    } else {
+        attempt.error("redirect to a private address")
+    }
+}
+
+// Resolves names for the page requests and drops private addresses, so a host that resolves
+// differently after `is_public` checked it (DNS rebinding) can't reach internal services
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addresses =
+                tokio::task::spawn_blocking(move || (host.as_str(), 0).to_socket_addrs())
+                    .await??
+                    .collect::<Vec<SocketAddr>>();
+            if addresses.is_empty() || !addresses.iter().all(|address| is_public_ip(&address.ip()))
+            {
+                return Err(format!("{} resolves to a private address", name.as_str()).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Box<dyn Iterator<Item = SocketAddr> + Send>)
+        })
+    }
+}
+
+// http(s) URL whose host resolves only to public addresses. Checked before the request for a
+// clear error, `PublicResolver` checks again when connecting.
+fn is_public(url: &Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return false;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match (host, port).to_socket_addrs() {
+        Ok(addresses) => {
+            let addresses: Vec<IpAddr> = addresses.map(|address| address.ip()).collect();
+            !addresses.is_empty() && addresses.iter().all(is_public_ip)
+        }
+        Err(_) => false,
+    }
+}
+
+fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Reserved 240.0.0.0/4, including the broadcast address
+                || a >= 240
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+                // Benchmarking, 198.18.0.0/15
+                || (a == 198 && (b == 18 || b == 19))
+                // IETF protocol assignments, 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(&IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    || ip.is_multicast()
+                    // NAT64, 64:ff9b::/96 and 64:ff9b:1::/48, reaches IPv4 addresses
+                    || (segments[0] == 0x64 && segments[1] == 0xff9b)
+                    // Documentation, 2001:db8::/32
+                    || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+                    // Deprecated IPv4-compatible ::a.b.c.d
+                    || segments[..6] == [0; 6])
+            }
+        },
+    }
+}
+
+fn extract_title(html: &str) -> Option<String> {
+    let re = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+    re.captures(html)
+        .and_then(|caps| caps.get(1))
+        .map(|m| m.as_str().split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|title| !title.is_empty())
+}
+
+// Drops navigation, scripts and other boilerplate, prefers <article> or <main> when present
+fn readable_text(html: &str) -> anyhow::Result<String> {
+    let boilerplate = Regex::new(
+        r"(?is)<(script|style|noscript|nav|header|footer|aside|form|svg|iframe)\b.*?</(script|style|noscript|nav|header|footer|aside|form|svg|iframe)>",
+    )
+    .unwrap();
+    let html = boilerplate.replace_all(html, "");
+    let main = Regex::new(r"(?is)<(article|main)\b[^>]*>(.*)</(article|main)>").unwrap();
+    let content = main
+        .captures(&html)
+        .and_then(|caps| caps.get(2))
+        .map_or(html.as_ref(), |m| m.as_str());
+    let decorator = html2text::render::text_renderer::TrivialDecorator::new();
+    let text =
+        html2text::config::with_decorator(decorator).string_from_read(content.as_bytes(), 10000)?;
+    let blank_lines = Regex::new(r"\n\s*\n+").unwrap();
+    Ok(blank_lines.replace_all(text.trim(), "\n\n").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public_ip(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        assert!(public("93.184.216.34"));
+        assert!(public("8.8.8.8"));
+        assert!(public("2606:4700::1111"));
+        assert!(public("::ffff:93.184.216.34"));
+    }
+
+    #[test]
+    fn private_and_reserved_ipv4_addresses_are_blocked() {
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "192.0.0.1",
+            "192.0.2.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!public(ip), "{} should be blocked", ip);
+        }
+    }
+
+    #[test]
+    fn private_and_reserved_ipv6_addresses_are_blocked() {
+        for ip in [
+            "::1",
+            "::",
+            "fc00::1",
+            "fe80::1",
+            "ff02::1",
+            "64:ff9b::7f00:1",
+            "64:ff9b:1::1",
+            "2001:db8::1",
+            "::ffff:127.0.0.1",
+            "::7f00:1",
+        ] {
+            assert!(!public(ip), "{} should be blocked", ip);
+        }
+    }
+
+    #[test]
+    fn literal_and_local_hosts_are_rejected() {
+        for url in [
+            "http://127.0.0.1:6333/collections",
+            "http://[::1]/",
+            "http://localhost:9000/metrics",
+            "ftp://93.184.216.34/file",
+            "file:///etc/passwd",
+        ] {
+            assert!(
+                !is_public(&Url::parse(url).unwrap()),
+                "{} should be rejected",
+                url
+            );
+        }
+        assert!(is_public(&Url::parse("http://93.184.216.34/").unwrap()));
+    }
+
+    #[test]
+    fn find_url_trims_trailing_punctuation() {
+        assert_eq!(
+            find_url("see https://example.com/a?b=1."),
+            Some("https://example.com/a?b=1".to_string())
+        );
+        assert_eq!(
+            find_url("(link: http://example.com/page)!"),
+            Some("http://example.com/page".to_string())
+        );
+        assert_eq!(
+            find_url("<https://example.com/x>"),
+            Some("https://example.com/x".to_string())
+        );
+        assert_eq!(find_url("no link here, example.com"), None);
+    }
+}